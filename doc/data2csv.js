@@ -1,5 +1,7 @@
 //! Convert a recorded file to CSV.
 //! Usage: node data2csv.js [input] [output]
+//! The samples are written in µV if the recording contains the configuration packet,
+//! otherwise as unsigned raw values.
 
 const fs = require('fs')
 
@@ -41,19 +43,43 @@ const main = () => {
   let data = readData(file)
   console.log(data.length)
   let csv = 'T,C1,C2,C3,C4,C5,C6,C7,C8\n'
-  let T = 0
+  let scale = null
   data.forEach(packet => {
-    if (packet.data[1] !== 8) return
-    let pos = 2
+    // Packet header version 1, see DataFormat.md
+    if (packet.data.length < 28 || packet.data.toString('latin1', 0, 2) !== 'BI' ||
+        packet.data[2] !== 1) return
+    let type = packet.data[3]
+    if (type === 3 && packet.data.length >= 60) {
+      scale = {
+        offset: packet.data.readInt32LE(52),
+        lsb: packet.data.readFloatLE(56)
+      }
+    }
+    // Compressed samples (encoding 1) have to be converted with the data-channel crate
+    if (type !== 0 || packet.data[24] !== 8 || (packet.data[27] & 15) !== 0) return
+    let signed = packet.data[25] === 1
+    // Packed with the bit depth of the header, 16 bit words at full depth
+    let bits = 16 - (packet.data[27] >> 4)
+    // Frames of lost packets leave a gap in T
+    let T = Number(packet.data.readBigUInt64LE(36))
+    let pos = 44 * 8
     let frame = []
-    while (pos < packet.data.length) {
-      frame.push(packet.data.readUInt16LE(pos))
+    while (pos + bits <= packet.data.length * 8) {
+      let word = 0
+      for (let j = 0; j < 3 && (pos >> 3) + j < packet.data.length; ++j) {
+        word |= packet.data[(pos >> 3) + j] << (8 * j)
+      }
+      let raw = ((word >> (pos & 7)) & ((1 << bits) - 1)) << (16 - bits)
+      if (signed && raw >= 32768) raw -= 65536
+      frame.push(scale
+        ? ((raw - scale.offset) * scale.lsb * 1e6).toFixed(3)
+        : signed ? raw + 32768 : raw)
       if (frame.length === 8) {
         csv += T + ',' + frame.join(',') + '\n'
         frame.length = 0
         T += 1
       }
-      pos += 2
+      pos += bits
     }
   })
   fs.writeFileSync(out, csv)
@@ -0,0 +1,165 @@
+//! Envelope of the samples for a live overview.
+//!
+//! The frames are split into intervals of whole cycles of the schedule, and for every interval
+//! only the smallest and the largest sample of each channel are sent. The overview looks the
+//! same as a plot of the full samples reduced the same way, at a fraction of the air time.
+
+use data_channel::{EnvelopeInfo, MinMax};
+use defmt::warn;
+use rhd2000_protocol::{Schedule, EMPTY_SLOT, MAX_DIVISOR, MAX_LANES};
+
+use crate::rhd2216::{Data, SampleFormat, MAX_CHANNELS};
+
+/// Maximum number of points of all channels in one packet.
+pub const MAX_POINTS: usize = 500;
+
+/// Completed points of the envelope, ready to be sent.
+pub struct Points<'a> {
+    /// Number of the packet. Missing numbers are lost packets.
+    pub sequence: u32,
+    /// Time in µs since boot of the first frame of the first point.
+    pub timestamp: u64,
+    /// Flags of all packets with samples covered by the points.
+    pub flags: u8,
+    /// Timing of the points.
+    pub info: EnvelopeInfo,
+    /// Smallest and largest sample of every channel, point by point.
+    pub points: &'a [MinMax],
+}
+
+/// Collects the envelope of the samples.
+pub struct Envelope {
+    /// Index of the channel of every sample in one cycle of the schedule.
+    slots: [u8; MAX_LANES * MAX_DIVISOR],
+    /// Number of samples in one cycle.
+    stride: usize,
+    /// Number of frames in one cycle.
+    cycle: u32,
+    /// Number of channels.
+    channels: usize,
+    /// Number of frames of every point, a multiple of the cycle.
+    frames_per_point: u32,
+    /// Mapping of the samples to unsigned order, so two's complement samples compare correctly.
+    order: u16,
+    /// Smallest and largest sample of every channel in the current interval, in unsigned order.
+    extremes: [MinMax; MAX_CHANNELS],
+    /// Number of frames in the current interval.
+    frames: u32,
+    /// First frame and time of the current interval.
+    interval: (u64, u64),
+    /// Index of the next frame of the command stream, `None` before the first samples.
+    next_frame: Option<u64>,
+    /// The completed points.
+    points: [MinMax; MAX_POINTS],
+    /// Number of values in `points`.
+    len: usize,
+    /// First frame and time of the first completed point.
+    start: (u64, u64),
+    /// Flags of the packets covered by the completed points.
+    flags: u8,
+    /// Number of the next packet.
+    sequence: u32,
+}
+
+impl Envelope {
+    /// An envelope of the channels in `channel_mask`, recorded with `schedule` at `frame_rate`
+    /// frames per second, with about `rate` points per second.
+    pub fn new(
+        schedule: &Schedule,
+        channel_mask: u64,
+        format: SampleFormat,
+        frame_rate: f32,
+        rate: u16,
+    ) -> Self {
+        let mut slots = [0; MAX_LANES * MAX_DIVISOR];
+        let mut stride = 0;
+        for amplifier in schedule.table().filter(|&s| s != EMPTY_SLOT) {
+            slots[stride] = (channel_mask & ((1 << amplifier) - 1)).count_ones() as u8;
+            stride += 1;
+        }
+        let cycle = schedule.cycle() as u32;
+        let frames = (frame_rate / rate.max(1) as f32) as u32;
+        Self {
+            slots,
+            stride: stride.max(1),
+            cycle,
+            channels: channel_mask.count_ones() as usize,
+            frames_per_point: frames.div_ceil(cycle).max(1) * cycle,
+            order: match format {
+                SampleFormat::OffsetBinary => 0,
+                SampleFormat::TwosComplement => 0x8000,
+            },
+            extremes: [(u16::MAX, 0); MAX_CHANNELS],
+            frames: 0,
+            interval: (0, 0),
+            next_frame: None,
+            points: [(0, 0); MAX_POINTS],
+            len: 0,
+            start: (0, 0),
+            flags: 0,
+            sequence: 0,
+        }
+    }
+    /// Add the samples of a packet. An interval with lost frames is dropped.
+    pub fn add(&mut self, d: &Data) {
+        if self.next_frame != Some(d.first_frame) {
+            self.frames = 0;
+        }
+        let cycles = d.frames.len() / self.stride;
+        self.next_frame = Some(d.first_frame + (cycles as u64) * self.cycle as u64);
+        for (i, samples) in d.frames.chunks_exact(self.stride).enumerate() {
+            if self.frames == 0 {
+                self.extremes = [(u16::MAX, 0); MAX_CHANNELS];
+                let frame = i as u64 * self.cycle as u64;
+                // The sample period counts 16MHz ticks.
+                let delay = frame * d.sample_period as u64 / 16;
+                self.interval = (d.first_frame + frame, d.timestamp + delay);
+            }
+            for (&s, &c) in samples.iter().zip(&self.slots) {
+                let (min, max) = &mut self.extremes[c as usize];
+                *min = (*min).min(s ^ self.order);
+                *max = (*max).max(s ^ self.order);
+            }
+            self.flags |= d.flags();
+            self.frames += self.cycle;
+            if self.frames >= self.frames_per_point {
+                self.frames = 0;
+                self.push();
+            }
+        }
+    }
+    /// Store the point of the current interval.
+    fn push(&mut self) {
+        let Some(points) = self.points.get_mut(self.len..self.len + self.channels) else {
+            warn!("Envelope point lost");
+            return;
+        };
+        if self.len == 0 {
+            self.start = self.interval;
+        }
+        for (p, (min, max)) in points.iter_mut().zip(self.extremes) {
+            *p = (min ^ self.order, max ^ self.order);
+        }
+        self.len += self.channels;
+    }
+    /// Take the completed points, if there are any.
+    pub fn take(&mut self, sample_period: u32) -> Option<Points<'_>> {
+        if self.len == 0 {
+            return None;
+        }
+        let len = core::mem::take(&mut self.len);
+        let sequence = self.sequence;
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(Points {
+            sequence,
+            timestamp: self.start.1,
+            flags: core::mem::take(&mut self.flags),
+            info: EnvelopeInfo {
+                sample_period,
+                frames_per_point: self.frames_per_point,
+                first_frame: self.start.0,
+            },
+            points: &self.points[..len],
+        })
+    }
+}
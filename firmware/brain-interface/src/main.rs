@@ -6,10 +6,15 @@
 
 extern crate alloc;
 
-use core::{cell::RefCell, ops::BitAnd};
+use core::{ops::BitAnd, ptr::NonNull};
 
-use data_channel::{BoxPacket, L2capError};
-use defmt::{info, unwrap, warn};
+use data_channel::{
+    compress, pack, packed_size, reduce, BitDepth, Command, DeviceStatus, EventMark, Header,
+    ImpedanceParams, L2capError, PacketPool, PacketType, Pool, PoolPacket, Rejection, Request,
+    Response, ResultCode, SampleEncoding, SampleInfo, ENVELOPE_INFO_SIZE, HEADER_SIZE,
+    SAMPLE_INFO_SIZE,
+};
+use defmt::{error, info, unwrap, warn};
 use embassy_executor::Spawner;
 use embassy_futures::join::join;
 use embassy_nrf::{
@@ -18,7 +23,8 @@ use embassy_nrf::{
     interrupt, peripherals,
     uarte::{self, UarteTx},
 };
-use embassy_time::Timer;
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant, Timer};
 use embedded_alloc::Heap;
 use nrf_softdevice::{
     ble::{
@@ -28,6 +34,7 @@ use nrf_softdevice::{
     },
     raw, Softdevice,
 };
+use rhd2000_protocol::{AMPLIFIER_LSB, AUX_LSB, EMPTY_SLOT, SUPPLY_LSB, TEMPERATURE_SCALE};
 
 // global logger
 use defmt_rtt as _;
@@ -35,14 +42,20 @@ use defmt_rtt as _;
 use embassy_nrf as _;
 use panic_probe as _;
 
+mod envelope;
+use envelope::{Envelope, Points};
+mod rhd2000;
+use rhd2000::{Acquisition, Rhd2000};
 mod rhd2216;
-use rhd2216::RHD2216;
+use rhd2216::{Data, SampleFormat, RHD2216};
 
-/// Use the embedded alloc heap to enable [`Vec`](alloc::vec::Vec) and [`BoxPacket`].
+/// The data channel crate links `alloc`, so a heap is needed even though nothing is allocated
+/// while recording.
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
 
 bind_interrupts!(struct Irqs {
+    TIMER1 => rhd2216::InterruptHandler;
     TIMER2 => rhd2216::InterruptHandler;
     UARTE1 => uarte::InterruptHandler<peripherals::UARTE1>;
 });
@@ -86,50 +99,658 @@ pub fn advertisement() -> ConnectableAdvertisement<'static> {
     }
 }
 
+/// Size of a packet in bytes.
+const PACKET_SIZE: usize = 2048;
+/// Number of packets that can be in flight at the same time.
+const PACKET_COUNT: usize = 24;
+
+/// Static buffer space for the packets.
+static PACKET_POOL: Pool<[u8; PACKET_SIZE], PACKET_COUNT> = Pool::new();
+
+/// The packet pool of this device.
+struct Packets;
+
+impl PacketPool for Packets {
+    const MTU: usize = PACKET_SIZE;
+    fn allocate() -> Option<NonNull<u8>> {
+        PACKET_POOL.allocate().map(NonNull::cast)
+    }
+    unsafe fn free(ptr: NonNull<u8>) {
+        PACKET_POOL.free(ptr.cast())
+    }
+}
+
 /// Alias for the packet type to have one place to change the size.
-type MyPacket = BoxPacket<2048>;
+type MyPacket = PoolPacket<Packets>;
+
+/// Interval at which the timing statistics are sent.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
 
-/// Shared state between the receiver and sender task.
-#[derive(defmt::Format)]
-struct State {
-    should_stop: bool,
+/// Number of commands that can wait for the sender task.
+const COMMAND_QUEUE: usize = 4;
+
+/// A packet from the host, passed from the receiver to the sender task.
+enum Incoming {
+    /// A command that could be decoded.
+    Request(Request),
+    /// A command that could not be decoded.
+    Rejected(Rejection),
+    /// The channel was closed.
+    Disconnected,
+}
+
+/// Queue of the commands for the sender task, which owns the RHD and answers them in order.
+type Commands = Channel<NoopRawMutex, Incoming, COMMAND_QUEUE>;
+
+/// What the sender task does next.
+enum Next {
+    /// Start the recording, or restart it with a new configuration,
+    /// and answer the request once it runs.
+    Record(Option<Request>),
+    /// Wait for commands without recording.
+    Idle,
+    /// Measure the impedances, answer the request and record again if `resume` is set.
+    MeasureImpedance {
+        request: Request,
+        params: ImpedanceParams,
+        resume: bool,
+    },
+    /// The channel was closed.
+    Disconnect,
+}
+
+/// Position of the running recording, used to estimate the frame being sampled.
+#[derive(Clone, Copy)]
+struct FrameClock {
+    /// Index of the first frame of the last packet.
+    first_frame: u64,
+    /// Time in µs since boot of the first frame of the last packet.
+    timestamp: u64,
+    /// Number of 16MHz ticks between two frames.
+    sample_period: u32,
 }
 
-/// Start the RHD and keep sending data packets over the L2CAP channel.
-async fn send_rhd_data(
-    rhd: &mut RHD2216<'_>,
+/// State of a connection, owned by the sender task.
+struct Session {
+    /// Configuration of the next recording.
+    config: rhd2216::Config,
+    /// How the samples are sent.
+    encoding: SampleEncoding,
+    /// Number of upper bits sent of every sample.
+    bit_depth: BitDepth,
+    /// Number of envelope points per second sent instead of the samples, 0 to send the samples.
+    envelope_rate: u16,
+    /// Difference between the time set by the host and the time since boot in µs.
+    time_offset: i64,
+    /// A recording is running.
+    recording: bool,
+    /// Sample rate of the running recording in Hz as achieved by the timer.
+    sample_rate: f32,
+    /// Position of the running recording, once its first packet has been read.
+    clock: Option<FrameClock>,
+}
+
+impl Session {
+    /// A new connection with the default configuration and the time since boot.
+    fn new() -> Self {
+        Self {
+            config: rhd2216::Config::default(),
+            encoding: SampleEncoding::Plain,
+            bit_depth: BitDepth::FULL,
+            envelope_rate: 0,
+            time_offset: 0,
+            recording: false,
+            sample_rate: 0.0,
+            clock: None,
+        }
+    }
+    /// Convert a time in µs since boot to the time of the host.
+    fn time(&self, uptime: u64) -> u64 {
+        uptime.wrapping_add_signed(self.time_offset)
+    }
+    /// Header of a packet that does not belong to a buffer of samples, stamped with the current time.
+    fn header(&self, packet_type: PacketType, channel_mask: u64, format: SampleFormat) -> Header {
+        Header {
+            packet_type,
+            sequence: 0,
+            timestamp: self.time(Instant::now().as_micros()),
+            channel_mask,
+            channels: channel_mask.count_ones() as u8,
+            format: format.into(),
+            flags: 0,
+            encoding: SampleEncoding::Plain,
+            bit_depth: BitDepth::FULL,
+        }
+    }
+    /// Estimated index of the frame being sampled, 0 if no samples have been read yet.
+    fn frame(&self) -> u64 {
+        self.clock.map_or(0, |c| {
+            let elapsed = Instant::now().as_micros().saturating_sub(c.timestamp);
+            let ticks = elapsed * (rhd2216::TIMER_FREQUENCY / 1_000_000) as u64;
+            c.first_frame + ticks / c.sample_period as u64
+        })
+    }
+}
+
+/// Send a packet over the L2CAP channel without waiting.
+/// The packet is dropped if the queue is full.
+fn try_send(
     channel: &l2cap::Channel<MyPacket>,
-    state: &RefCell<State>,
+    packet: MyPacket,
 ) -> Result<(), L2capError<MyPacket>> {
+    channel.try_tx(packet).or_else(|e| {
+        if let l2cap::TxError::TxQueueFull(_) = e {
+            warn!("Packet lost");
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })?;
+    Ok(())
+}
+
+/// Measure the electrode impedances with `params` and send the report over the L2CAP channel.
+/// Returns the result for the response to the command.
+async fn send_impedance_report(
+    rhd: &mut impl Rhd2000,
+    channel: &l2cap::Channel<MyPacket>,
+    session: &Session,
+    params: &ImpedanceParams,
+) -> Result<ResultCode, L2capError<MyPacket>> {
+    info!("Measuring impedance at {}Hz", params.frequency);
+    let report = match rhd.measure_impedance(&session.config, &params.into()).await {
+        Ok(report) => report,
+        Err(e) => {
+            warn!("Impedance measurement failed: {}", e);
+            return Ok(ResultCode::MeasurementFailed);
+        }
+    };
+    let mut packet = MyPacket::new().ok_or(RxError::AllocateFailed)?;
+    let header = session.header(
+        PacketType::Impedance,
+        report.channel_mask,
+        SampleFormat::OffsetBinary,
+    );
+    packet.append(&header.encode());
+    packet.append(&report.frequency.to_le_bytes());
+    for (c, z) in report.channels.iter().enumerate() {
+        if report.channel_mask & (1u64 << c) != 0 {
+            packet.append(&z.magnitude.to_le_bytes());
+            packet.append(&z.phase.to_le_bytes());
+        }
+    }
+    channel.tx(packet).await?;
+    Ok(ResultCode::Ok)
+}
+
+/// Send the chip identification, the register values, the scale factors
+/// and the schedule of the recording.
+async fn send_configuration(
+    rhd: &impl Acquisition,
+    session: &Session,
+    channel: &l2cap::Channel<MyPacket>,
+) -> Result<(), L2capError<MyPacket>> {
+    let chip = rhd.chip_info();
+    let format = rhd.format();
+    let mut packet = MyPacket::new().ok_or(RxError::AllocateFailed)?;
+    packet.append(
+        &session
+            .header(PacketType::Config, rhd.channel_mask(), format)
+            .encode(),
+    );
+    packet.append(&[
+        chip.chip_id,
+        chip.die_revision,
+        chip.amplifier_count,
+        session.config.signal.map_or(0, |s| s.kind()),
+    ]);
+    packet.append(&rhd.registers().encode());
+    packet.append(&[0, 0]);
+    packet.append(&format.zero().to_le_bytes());
+    for scale in [AMPLIFIER_LSB, AUX_LSB, SUPPLY_LSB, 1.0 / TEMPERATURE_SCALE] {
+        packet.append(&scale.to_le_bytes());
+    }
+    let schedule = rhd.schedule();
+    packet.append(&[schedule.cycle() as u8, schedule.lanes() as u8, 0, 0]);
+    for slot in schedule.table() {
+        packet.append(&[slot]);
+    }
+    channel.tx(packet).await?;
+    Ok(())
+}
+
+/// Send the timing statistics of the command stream over the L2CAP channel without waiting.
+fn send_statistics(
+    rhd: &mut impl Acquisition,
+    session: &Session,
+    channel: &l2cap::Channel<MyPacket>,
+) -> Result<(), L2capError<MyPacket>> {
+    let stats = rhd.statistics();
+    let Some(mut packet) = MyPacket::new() else {
+        warn!("Packet lost");
+        return Ok(());
+    };
+    let header = session.header(PacketType::Statistics, rhd.channel_mask(), rhd.format());
+    packet.append(&header.encode());
+    packet.append(&stats.interrupts.to_le_bytes());
+    for v in [
+        stats.mean_latency,
+        stats.max_latency,
+        stats.overflow_margin,
+        stats.max_critical_section,
+    ] {
+        packet.append(&v.to_le_bytes());
+    }
+    packet.append(&stats.max_overflow_words.to_le_bytes());
+    packet.append(&stats.overflow_capacity.to_le_bytes());
+    try_send(channel, packet)
+}
+
+/// Send the response to a command, followed by its `data`.
+async fn send_response(
+    channel: &l2cap::Channel<MyPacket>,
+    session: &Session,
+    response: Response,
+    data: &[u8],
+) -> Result<(), L2capError<MyPacket>> {
+    let mut packet = MyPacket::new().ok_or(RxError::AllocateFailed)?;
+    let config = &session.config;
+    packet.append(
+        &session
+            .header(PacketType::Response, config.channel_mask, config.format)
+            .encode(),
+    );
+    packet.append(&response.encode());
+    packet.append(data);
+    channel.tx(packet).await?;
+    Ok(())
+}
+
+/// Tell the host that the recording has stopped without a command.
+async fn send_stopped(
+    channel: &l2cap::Channel<MyPacket>,
+    session: &Session,
+) -> Result<(), L2capError<MyPacket>> {
+    let mut packet = MyPacket::new().ok_or(RxError::AllocateFailed)?;
+    let config = &session.config;
+    packet.append(
+        &session
+            .header(PacketType::Status, config.channel_mask, config.format)
+            .encode(),
+    );
+    let status = DeviceStatus {
+        recording: false,
+        sample_rate: 0.0,
+        frame: session.frame(),
+    };
+    packet.append(&status.encode());
+    channel.tx(packet).await?;
+    Ok(())
+}
+
+/// Carry out a command of the host and answer it.
+/// Returns what to do next if the command starts, stops or restarts the recording
+/// or measures the impedances.
+/// A command that starts a recording is answered once the recording runs.
+async fn handle_command(
+    incoming: Incoming,
+    session: &mut Session,
+    channel: &l2cap::Channel<MyPacket>,
+) -> Result<Option<Next>, L2capError<MyPacket>> {
+    let request = match incoming {
+        Incoming::Request(request) => request,
+        Incoming::Rejected(rejection) => {
+            warn!("Rejected command: {}", rejection);
+            send_response(channel, session, rejection.into(), &[]).await?;
+            return Ok(None);
+        }
+        Incoming::Disconnected => return Ok(Some(Next::Disconnect)),
+    };
+    info!("Command {}", request.command);
+    let ok = Response::to(&request, ResultCode::Ok);
+    match request.command {
+        Command::Start if !session.recording => return Ok(Some(Next::Record(Some(request)))),
+        Command::Start => send_response(channel, session, ok, &[]).await?,
+        Command::Stop => {
+            send_response(channel, session, ok, &[]).await?;
+            if session.recording {
+                return Ok(Some(Next::Idle));
+            }
+        }
+        Command::SetConfig(config) => {
+            session.config = (&config).into();
+            session.encoding = config.encoding;
+            session.bit_depth = config.bit_depth;
+            session.envelope_rate = config.envelope_rate;
+            if session.recording {
+                return Ok(Some(Next::Record(Some(request))));
+            }
+            send_response(channel, session, ok, &[]).await?;
+        }
+        Command::Status => {
+            let status = DeviceStatus {
+                recording: session.recording,
+                sample_rate: session.sample_rate,
+                frame: session.frame(),
+            };
+            send_response(channel, session, ok, &status.encode()).await?;
+        }
+        Command::Ping(token) => send_response(channel, session, ok, &token.to_le_bytes()).await?,
+        Command::SetTime(time) => {
+            session.time_offset = time.wrapping_sub(Instant::now().as_micros()) as i64;
+            send_response(channel, session, ok, &[]).await?;
+        }
+        Command::MeasureImpedance(params) => {
+            return Ok(Some(Next::MeasureImpedance {
+                request,
+                params,
+                resume: session.recording,
+            }))
+        }
+        Command::MarkEvent(id) => {
+            let mark = EventMark {
+                id,
+                frame: session.frame(),
+            };
+            send_response(channel, session, ok, &mark.encode()).await?;
+        }
+    }
+    Ok(None)
+}
+
+/// Send the samples of a buffer over the L2CAP channel, reduced to the bit depth of the session
+/// and compressed if the session asks for it.
+fn send_samples(
+    channel: &l2cap::Channel<MyPacket>,
+    session: &Session,
+    d: &mut Data,
+    mut header: Header,
+    stride: usize,
+) -> Result<(), L2capError<MyPacket>> {
+    let Some(mut packet) = MyPacket::new() else {
+        warn!("Packet lost");
+        return Ok(());
+    };
+    assert!(HEADER_SIZE + SAMPLE_INFO_SIZE + 2 * d.frames.len() <= MyPacket::MTU);
+    packet.append(&header.encode());
+    let (first_marked, marked_count) = d
+        .status
+        .marker
+        .map_or((0, 0), |m| (m.first_frame, m.frame_count));
+    let info = SampleInfo {
+        sample_period: d.sample_period,
+        first_marked,
+        marked_count,
+        first_frame: d.first_frame,
+    };
+    packet.append(&info.encode());
+    let bit_depth = session.bit_depth.get();
+    if session.bit_depth != BitDepth::FULL {
+        reduce(&mut d.frames, bit_depth, d.format.into());
+    }
+    // The samples are only compressed if that makes them smaller than the packed samples.
+    let packed = packed_size(d.frames.len(), bit_depth);
+    if session.encoding == SampleEncoding::Rice
+        && packet.append_with(packed, |space| {
+            compress(&d.frames, stride, space).unwrap_or(0)
+        }) > 0
+    {
+        header.encoding = SampleEncoding::Rice;
+        packet[..HEADER_SIZE].copy_from_slice(&header.encode());
+    } else {
+        packet.append_with(packed, |space| pack(&d.frames, bit_depth, space));
+    }
+    try_send(channel, packet)
+}
+
+/// Send the completed points of the envelope over the L2CAP channel.
+/// The `header` of the samples they replace provides the channels and the format.
+fn send_envelope(
+    channel: &l2cap::Channel<MyPacket>,
+    session: &Session,
+    header: &Header,
+    points: Points,
+) -> Result<(), L2capError<MyPacket>> {
+    let Some(mut packet) = MyPacket::new() else {
+        warn!("Packet lost");
+        return Ok(());
+    };
+    assert!(HEADER_SIZE + ENVELOPE_INFO_SIZE + 4 * points.points.len() <= MyPacket::MTU);
+    let header = Header {
+        packet_type: PacketType::Envelope,
+        sequence: points.sequence,
+        timestamp: session.time(points.timestamp),
+        flags: points.flags,
+        bit_depth: BitDepth::FULL,
+        ..*header
+    };
+    packet.append(&header.encode());
+    packet.append(&points.info.encode());
+    for (min, max) in points.points {
+        packet.append(&min.to_le_bytes());
+        packet.append(&max.to_le_bytes());
+    }
+    try_send(channel, packet)
+}
+
+/// Start the RHD with the configuration of the session and keep sending data packets over the
+/// L2CAP channel until a command stops or restarts the recording.
+/// The `request` that started the recording is answered once it runs.
+/// The timing statistics are sent every [`STATS_INTERVAL`].
+async fn record(
+    rhd: &mut impl Rhd2000,
+    channel: &l2cap::Channel<MyPacket>,
+    commands: &Commands,
+    session: &mut Session,
+    request: Option<Request>,
+) -> Result<Next, L2capError<MyPacket>> {
     info!("Starting");
-    let mut rhd = rhd.start();
-    loop {
-        if state.borrow().should_stop {
-            return Ok(());
+    let mut rhd = match rhd.start(&session.config).await {
+        Ok(rhd) => rhd,
+        Err(e) => {
+            error!("Could not start the RHD: {}", e);
+            if let Some(request) = request {
+                let response = Response::to(&request, ResultCode::StartFailed);
+                send_response(channel, session, response, &[]).await?;
+            }
+            return Ok(Next::Idle);
+        }
+    };
+    session.recording = true;
+    session.sample_rate = rhd.timing().sample_rate();
+    info!("Found {}", rhd.chip_info());
+    info!("Sampling at {}Hz", rhd.timing().sample_rate());
+    info!(
+        "Bandwidth {}Hz to {}Hz",
+        rhd.bandwidth().lower(),
+        rhd.bandwidth().upper()
+    );
+    if let Some(dsp) = rhd.dsp_filter() {
+        info!("Offset removal above {}Hz", dsp.cutoff());
+    }
+    if let Some(signal) = session.config.signal {
+        info!("Replacing the samples with {}", signal);
+    }
+    send_configuration(&rhd, session, channel).await?;
+    if let Some(request) = request {
+        send_response(
+            channel,
+            session,
+            Response::to(&request, ResultCode::Ok),
+            &[],
+        )
+        .await?;
+    }
+    // Samples repeat their channels once per cycle of the schedule.
+    let stride = rhd.schedule().table().filter(|&s| s != EMPTY_SLOT).count();
+    let mut envelope = (session.envelope_rate > 0).then(|| {
+        info!(
+            "Sending {} envelope points per second",
+            session.envelope_rate
+        );
+        Envelope::new(
+            &rhd.schedule(),
+            rhd.channel_mask(),
+            rhd.format(),
+            rhd.timing().sample_rate(),
+            session.envelope_rate,
+        )
+    });
+    let mut last_stats = Instant::now();
+    let next = loop {
+        // The commands are taken between two packets, so the RHD is only stopped between reads.
+        if let Ok(incoming) = commands.try_receive() {
+            if let Some(next) = handle_command(incoming, session, channel).await? {
+                break next;
+            }
         }
-        let d = rhd.read().await;
-        let mut packet = MyPacket::new().ok_or(RxError::AllocateFailed)?;
-        assert!(MyPacket::MTU / 2 > d.frames.len());
-        packet.append(&[(d.sequence_number & 255) as u8, d.channels as u8]);
-        for v in &d.frames {
-            packet.append(&v.to_le_bytes());
+        if last_stats.elapsed() >= STATS_INTERVAL {
+            last_stats = Instant::now();
+            send_statistics(&mut rhd, session, channel)?;
         }
-        channel.try_tx(packet).or_else(|e| {
-            if let l2cap::TxError::TxQueueFull(_) = e {
+        let mut d = match rhd.read().await {
+            Ok(d) => d,
+            Err(e) => {
+                error!("The RHD stopped responding: {}", e);
+                send_stopped(channel, session).await?;
+                break Next::Idle;
+            }
+        };
+        session.clock = Some(FrameClock {
+            first_frame: d.first_frame,
+            timestamp: d.timestamp,
+            sample_period: d.sample_period,
+        });
+        let header = Header {
+            packet_type: PacketType::Samples,
+            sequence: d.sequence_number as u32,
+            timestamp: session.time(d.timestamp),
+            channel_mask: d.channel_mask,
+            channels: d.channels as u8,
+            format: d.format.into(),
+            flags: d.flags(),
+            encoding: SampleEncoding::Plain,
+            bit_depth: session.bit_depth,
+        };
+        // With an envelope the auxiliary measurements are only sent along with its packets.
+        let send_aux = if let Some(envelope) = &mut envelope {
+            envelope.add(&d);
+            match envelope.take(d.sample_period) {
+                Some(points) => {
+                    send_envelope(channel, session, &header, points)?;
+                    true
+                }
+                None => false,
+            }
+        } else {
+            send_samples(channel, session, &mut d, header, stride)?;
+            true
+        };
+        if let Some(aux) = d.aux.filter(|_| send_aux) {
+            let Some(mut packet) = MyPacket::new() else {
                 warn!("Packet lost");
-                Ok(())
-            } else {
-                Err(e)
+                continue;
+            };
+            let header = Header {
+                packet_type: PacketType::Aux,
+                bit_depth: BitDepth::FULL,
+                ..header
+            };
+            packet.append(&header.encode());
+            for v in aux.inputs {
+                packet.append(&v.to_le_bytes());
             }
-        })?;
+            packet.append(&aux.supply.to_le_bytes());
+            packet.append(&aux.temperature.unwrap_or(f32::NAN).to_le_bytes());
+            try_send(channel, packet)?;
+        }
+    };
+    session.recording = false;
+    session.sample_rate = 0.0;
+    session.clock = None;
+    Ok(next)
+}
+
+/// Wait for commands without recording.
+async fn idle(
+    channel: &l2cap::Channel<MyPacket>,
+    commands: &Commands,
+    session: &mut Session,
+) -> Result<Next, L2capError<MyPacket>> {
+    info!("Idle");
+    loop {
+        if let Some(next) = handle_command(commands.receive().await, session, channel).await? {
+            return Ok(next);
+        }
     }
 }
 
-/// Receive commands and interpret them.
-async fn receive_commands(channel: &l2cap::Channel<MyPacket>, state: &RefCell<State>) -> () {
-    if let Ok(_) = channel.rx().await {
-        state.borrow_mut().should_stop = true;
+/// Measure the impedances with the recording stopped and answer the `request` once the report
+/// is sent. The recording continues afterwards if it was running.
+async fn measure_impedance(
+    rhd: &mut impl Rhd2000,
+    channel: &l2cap::Channel<MyPacket>,
+    session: &Session,
+    request: Request,
+    params: &ImpedanceParams,
+    resume: bool,
+) -> Result<Next, L2capError<MyPacket>> {
+    let result = send_impedance_report(rhd, channel, session, params).await?;
+    send_response(channel, session, Response::to(&request, result), &[]).await?;
+    Ok(if resume {
+        Next::Record(None)
+    } else {
+        Next::Idle
+    })
+}
+
+/// Start recording and answer the commands of the host until the channel is closed.
+async fn run_session(
+    rhd: &mut impl Rhd2000,
+    channel: &l2cap::Channel<MyPacket>,
+    commands: &Commands,
+) -> Result<(), L2capError<MyPacket>> {
+    let mut session = Session::new();
+    let mut next = Next::Record(None);
+    loop {
+        next = match next {
+            Next::Record(request) => record(rhd, channel, commands, &mut session, request).await?,
+            Next::Idle => idle(channel, commands, &mut session).await?,
+            Next::MeasureImpedance {
+                request,
+                params,
+                resume,
+            } => measure_impedance(rhd, channel, &session, request, &params, resume).await?,
+            Next::Disconnect => return Ok(()),
+        };
+    }
+}
+
+/// Serve the host over the L2CAP channel.
+/// After an error the commands are discarded until the channel is closed,
+/// so the receiver task is not stuck on a full queue.
+async fn serve_connection(
+    rhd: &mut impl Rhd2000,
+    channel: &l2cap::Channel<MyPacket>,
+    commands: &Commands,
+) -> Result<(), L2capError<MyPacket>> {
+    let result = run_session(rhd, channel, commands).await;
+    if result.is_err() {
+        while !matches!(commands.receive().await, Incoming::Disconnected) {}
+    }
+    result
+}
+
+/// Receive commands and queue them for the sender task until the channel is closed.
+async fn receive_commands(channel: &l2cap::Channel<MyPacket>, commands: &Commands) {
+    while let Ok(packet) = channel.rx().await {
+        let incoming = match Command::decode(&packet) {
+            Ok(request) => Incoming::Request(request),
+            Err(rejection) => Incoming::Rejected(rejection),
+        };
+        commands.send(incoming).await;
     }
+    commands.send(Incoming::Disconnected).await;
 }
 
 /// The main task.
@@ -140,7 +761,7 @@ async fn main(spawner: Spawner) {
     // Initialise allocator
     {
         use core::mem::MaybeUninit;
-        const HEAP_SIZE: usize = 1024 * 128;
+        const HEAP_SIZE: usize = 1024 * 4;
         static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
         unsafe { HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE) }
     }
@@ -256,10 +877,10 @@ async fn main(spawner: Spawner) {
             let config = nrf_softdevice::ble::l2cap::Config { credits: 3 };
             let channel = l2cap.listen(&connection, &config, data_channel::PSM).await;
             if let Ok(channel) = channel {
-                let state = RefCell::new(State { should_stop: false });
+                let commands = Commands::new();
                 let _result = join(
-                    send_rhd_data(&mut rhd, &channel, &state),
-                    receive_commands(&channel, &state),
+                    serve_connection(&mut rhd, &channel, &commands),
+                    receive_commands(&channel, &commands),
                 )
                 .await;
                 info!("{}", _result);
@@ -0,0 +1,110 @@
+//! Common interface for the chips of the RHD2000 family.
+//!
+//! All chips of the family share the same command protocol.
+//! They differ in the number of amplifiers, whether the amplifiers are bipolar and,
+//! on the RHD2164, in the double data rate MISO for the amplifiers 32 to 63.
+//! The variant is detected from the chip ID in ROM register 63, so the same firmware
+//! can be used with the RHD2132 and the RHD2216.
+//! The RHD2164 is recognised but not supported, the SPI cannot read the double data rate MISO.
+
+use futures::Future;
+use rhd2000_protocol::{RegisterMap, Schedule};
+
+use crate::rhd2216::{
+    Bandwidth, ChipInfo, Config, Data, DspFilter, Error, ImpedanceConfig, ImpedanceReport,
+    SampleFormat, Statistics, Status, Timing,
+};
+
+/// A chip of the RHD2000 family.
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Variant {
+    /// 32 unipolar amplifiers.
+    Rhd2132,
+    /// 16 bipolar amplifiers.
+    Rhd2216,
+    /// 64 unipolar amplifiers with double data rate MISO.
+    Rhd2164,
+}
+
+impl Variant {
+    /// Get the variant for the chip ID from ROM register 63.
+    pub fn from_chip_id(chip_id: u8) -> Option<Self> {
+        match chip_id {
+            1 => Some(Self::Rhd2132),
+            2 => Some(Self::Rhd2216),
+            4 => Some(Self::Rhd2164),
+            _ => None,
+        }
+    }
+    /// Number of amplifiers on the chip.
+    pub fn amplifier_count(self) -> usize {
+        match self {
+            Self::Rhd2132 => 32,
+            Self::Rhd2216 => 16,
+            Self::Rhd2164 => 64,
+        }
+    }
+    /// Whether the amplifiers are bipolar.
+    pub fn bipolar(self) -> bool {
+        self == Self::Rhd2216
+    }
+    /// Whether the driver supports the chip.
+    /// The RHD2164 sends the results of the amplifiers 32 to 63 on the falling clock edges
+    /// and has the additional registers 18 to 21, neither of which is implemented.
+    pub fn supported(self) -> bool {
+        self != Self::Rhd2164
+    }
+    /// Mask of the amplifiers of the chip.
+    pub fn channel_mask(self) -> u64 {
+        u64::MAX >> (64 - self.amplifier_count())
+    }
+}
+
+/// A driver for a chip of the RHD2000 family.
+pub trait Rhd2000 {
+    /// Token for a running acquisition. The acquisition stops when it is dropped.
+    type Running<'a>: Acquisition
+    where
+        Self: 'a;
+    /// Start the acquisition with the given configuration.
+    fn start(&mut self, config: &Config) -> impl Future<Output = Result<Self::Running<'_>, Error>>;
+    /// Measure the impedance of the electrodes selected in the configuration.
+    fn measure_impedance(
+        &mut self,
+        config: &Config,
+        impedance: &ImpedanceConfig,
+    ) -> impl Future<Output = Result<ImpedanceReport, Error>>;
+}
+
+/// A running acquisition.
+pub trait Acquisition {
+    /// Wait until a data packet from the ADC is ready.
+    /// Fails if the chip stopped responding and could not be started again.
+    /// The ADC is stopped then and no more packets follow.
+    fn read(&mut self) -> impl Future<Output = Result<Data, Error>>;
+    /// Get the timing the ADC is running with.
+    fn timing(&self) -> Timing;
+    /// Get the amplifier bandwidth the ADC is running with.
+    fn bandwidth(&self) -> Bandwidth;
+    /// Get the DSP offset removal the ADC is running with, if it is enabled.
+    fn dsp_filter(&self) -> Option<DspFilter>;
+    /// Get the information read from the chip during startup.
+    fn chip_info(&self) -> ChipInfo;
+    /// Get the register values written during startup and verified by reading them back.
+    fn registers(&self) -> RegisterMap;
+    /// Get the mask of the recorded amplifier channels.
+    fn channel_mask(&self) -> u64;
+    /// Get the encoding of the samples. [`SampleFormat::volts`] converts them to V.
+    fn format(&self) -> SampleFormat;
+    /// Get the order in which the channels are converted.
+    fn schedule(&self) -> Schedule;
+    /// Get the timing statistics of the command stream since the last call and reset them.
+    fn statistics(&mut self) -> Statistics;
+    /// Recalibrate the ADC without stopping the acquisition.
+    /// The affected frames are marked in the [`Status`] of their packet.
+    fn recalibrate(&mut self);
+    /// Let the amplifiers settle quickly after large signals by shorting their inputs
+    /// for at least `duration_ms` milliseconds.
+    /// The affected frames are marked in the [`Status`] of their packet.
+    fn fast_settle(&mut self, duration_ms: u32);
+}
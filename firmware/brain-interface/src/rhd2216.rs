@@ -1,15 +1,17 @@
 //! Driver for the RHD2216 Electrophysiological ADC
 //!
+//! The driver also works with the other chips of the RHD2000 family.
+//! The chip variant is detected during startup, see [`Variant`].
+//!
 //! Once started the driver generates a steady command stream on the SPI3 interface.
 //! Use of the SPI3 interface is necessary because it is the only one supporting automatic
 //! handling of the chip select pin.
 //!
 //! To make the code easier the timers are hardcoded to TIMER1 and TIMER2.
 
-use alloc::vec::Vec;
-use core::{arch::asm, cell::RefCell, marker::PhantomData, ptr::NonNull};
+use core::{cell::RefCell, marker::PhantomData, ops::Range, ptr::NonNull};
 use critical_section::Mutex;
-use defmt::{info, warn};
+use defmt::warn;
 use embassy_nrf::{
     gpio::{AnyPin, Pin, Port},
     interrupt::{self, typelevel::Interrupt},
@@ -17,30 +19,333 @@ use embassy_nrf::{
     ppi::{AnyConfigurableChannel, Event, Ppi, Task},
     timer, Peripheral, PeripheralRef,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_sync::{
+    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+};
 use futures::Future;
+use rhd2000_protocol::{
+    amplifier_volts, convert_channel, dummy_command, fill_readout_commands, fill_startup_commands,
+    power_down_commands, start_calibration, write_register, RegisterMap, Schedule, ScheduleError,
+    Waveform, AMPLIFIER_ZERO,
+};
+
+mod auxiliary;
+pub use auxiliary::AuxData;
+use auxiliary::AuxSchedule;
+mod bandwidth;
+pub use bandwidth::Bandwidth;
+mod dsp;
+pub use dsp::DspFilter;
+mod frames;
+pub use frames::{Frames, MAX_SAMPLES};
+mod impedance;
+use impedance::{Correlator, ImpedanceTest};
+pub use impedance::{Impedance, ImpedanceConfig, ImpedanceReport, ImpedanceScale};
+mod operation;
+pub use operation::{Marker, Operation};
+mod self_test;
+pub use self_test::ChipInfo;
+mod stats;
+pub use stats::Statistics;
+use stats::{with_buffers, Counters};
+mod swap;
+mod synthetic;
+use swap::{Swap, SAFE_START};
+
+use crate::rhd2000::{Acquisition, Rhd2000, Variant};
 
 // Configuration
-/// Number of channels.
-pub const CHANNEL_COUNT: usize = 8;
-/// Starting channel.
-const SKIP_CHANNELS: usize = 4;
-/// How many samples to read from each channel between interrupts.
-pub const FRAMES_PER_BUFFER: usize = 50;
-/// How many commands to send for each frame. Must be at least [`CHANNEL_COUNT`] + 2.
-const STRIDE: usize = 10;
-/// Number of 16MHz ticks between two commands.
-const TIMER_INTERVAL: usize = 640;
-/// Size of one full buffer between interrupts.
-const BUFFER_SIZE: usize = FRAMES_PER_BUFFER * STRIDE;
+/// Highest number of amplifier channels on a chip of the family.
+pub const MAX_AMPLIFIERS: usize = 64;
+/// Maximum number of channels that can be recorded at the same time.
+pub const MAX_CHANNELS: usize = 32;
+/// Frequency of the timer that generates the command interval.
+pub const TIMER_FREQUENCY: u32 = 16_000_000;
+/// Minimum number of 16MHz ticks between two commands.
+/// The SPI transaction and the safe window for the buffer swap must fit in between.
+const MIN_TIMER_INTERVAL: u32 = 80;
+/// Maximum number of 16MHz ticks between two commands.
+const MAX_TIMER_INTERVAL: u32 = 0xffff;
+/// Maximum number of commands to send for each frame.
+const MAX_STRIDE: usize = MAX_CHANNELS + 2;
+/// Minimum size of one buffer. The startup sequence must fit into a single buffer.
+const MIN_BUFFER_SIZE: usize = 256;
+/// Maximum size of one buffer between interrupts.
+const MAX_BUFFER_SIZE: usize = 1000;
 /// How much overflow space to leave after every buffer.
-const OVERFLOW: usize = BUFFER_SIZE;
+/// The slots transferred before the buffers are swapped land there.
+/// After a missed swap a whole buffer lands there, followed by the slots until the retry.
+const OVERFLOW: usize = MAX_BUFFER_SIZE + SWAP_SLACK;
+/// Number of slots after a missed swap that fit into the overflow space before it is retried.
+const SWAP_SLACK: usize = 64;
 /// Total buffer space.
-const TOTAL_BUFFER: usize = BUFFER_SIZE + OVERFLOW;
-/// By how many bytes to adjust the DMA pointer. Must be the byte size of one buffer.
-const OFFSET: u32 = BUFFER_SIZE as u32 * 2;
-/// Mask of active channels.
-const CHANNEL_MASK: u32 = ((1 << CHANNEL_COUNT) - 1) << SKIP_CHANNELS;
+const TOTAL_BUFFER: usize = MAX_BUFFER_SIZE + OVERFLOW;
+/// Targeted number of 16MHz ticks between two interrupts.
+const BUFFER_PERIOD: u32 = TIMER_FREQUENCY / 50;
+/// Number of consecutive buffers with bad responses after which the chip is re-initialised.
+const REINIT_AFTER: usize = 5;
+/// Number of attempts to re-initialise the chip before the recording is given up.
+const REINIT_ATTEMPTS: usize = 3;
+
+/// Configuration for a recording session.
+#[derive(Clone, Debug, defmt::Format)]
+pub struct Config {
+    /// Bit mask of the amplifier channels to record.
+    /// Bit `n` selects amplifier `n`. The bits do not have to be contiguous.
+    pub channel_mask: u64,
+    /// Requested sample rate per channel in Hz.
+    /// The achieved rate is reported by [`Timing::sample_rate`].
+    pub sample_rate: u32,
+    /// Rate divisor of each amplifier channel.
+    /// Channel `n` is sampled at the sample rate divided by `divisors[n]`.
+    /// The divisors must be powers of two up to [`MAX_DIVISOR`].
+    ///
+    /// [`MAX_DIVISOR`]: rhd2000_protocol::MAX_DIVISOR
+    pub divisors: [u8; MAX_AMPLIFIERS],
+    /// Requested upper cutoff of the amplifiers in Hz.
+    /// The realised cutoff is reported by [`Bandwidth::upper`].
+    pub upper_bandwidth: f32,
+    /// Requested lower cutoff of the amplifiers in Hz.
+    /// The realised cutoff is reported by [`Bandwidth::lower`].
+    pub lower_bandwidth: f32,
+    /// Requested cutoff of the DSP offset removal in Hz, or `None` to disable it.
+    /// The realised cutoff is reported by [`DspFilter::cutoff`].
+    /// It cannot be combined with rate divisors other than 1.
+    pub dsp_cutoff: Option<f32>,
+    /// Encoding of the samples.
+    pub format: SampleFormat,
+    /// Replace the samples with a synthetic signal to test the data path.
+    /// The chip keeps running, only the samples are replaced.
+    pub signal: Option<Waveform>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            channel_mask: 0x0ff0,
+            sample_rate: 2500,
+            divisors: [1; MAX_AMPLIFIERS],
+            upper_bandwidth: 1000.0,
+            lower_bandwidth: 1.0,
+            dsp_cutoff: None,
+            format: SampleFormat::OffsetBinary,
+            signal: None,
+        }
+    }
+}
+
+/// Encoding of the samples.
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum SampleFormat {
+    /// Unsigned with 32768 for 0V.
+    OffsetBinary,
+    /// Two's complement. The samples have to be reinterpreted as `i16`.
+    TwosComplement,
+}
+
+impl From<SampleFormat> for data_channel::SampleFormat {
+    fn from(format: SampleFormat) -> Self {
+        match format {
+            SampleFormat::OffsetBinary => Self::OffsetBinary,
+            SampleFormat::TwosComplement => Self::TwosComplement,
+        }
+    }
+}
+
+impl From<data_channel::SampleFormat> for SampleFormat {
+    fn from(format: data_channel::SampleFormat) -> Self {
+        match format {
+            data_channel::SampleFormat::OffsetBinary => Self::OffsetBinary,
+            data_channel::SampleFormat::TwosComplement => Self::TwosComplement,
+        }
+    }
+}
+
+impl From<&data_channel::RecordingConfig> for Config {
+    fn from(config: &data_channel::RecordingConfig) -> Self {
+        Self {
+            channel_mask: config.channel_mask,
+            sample_rate: config.sample_rate,
+            divisors: config.divisors,
+            upper_bandwidth: config.upper_bandwidth,
+            lower_bandwidth: config.lower_bandwidth,
+            dsp_cutoff: (config.dsp_cutoff > 0.0).then_some(config.dsp_cutoff),
+            format: config.format.into(),
+            signal: Waveform::decode(&config.signal),
+        }
+    }
+}
+
+impl SampleFormat {
+    /// Value of a sample at 0V after reinterpreting it in this encoding.
+    pub fn zero(&self) -> i32 {
+        match self {
+            Self::OffsetBinary => AMPLIFIER_ZERO as i32,
+            Self::TwosComplement => 0,
+        }
+    }
+    /// Input voltage of a sample in V.
+    pub fn volts(&self, sample: u16) -> f32 {
+        amplifier_volts(sample, *self == Self::TwosComplement)
+    }
+}
+
+/// Settings of a recording session derived from a checked [`Config`].
+#[derive(Clone, Copy)]
+struct Settings {
+    /// Mask of the active channels.
+    channel_mask: u64,
+    /// Order of the conversions.
+    schedule: Schedule,
+    /// Timing of the command stream.
+    timing: Timing,
+    /// Amplifier bandwidth settings.
+    bandwidth: Bandwidth,
+    /// DSP offset removal settings.
+    dsp: Option<DspFilter>,
+    /// Encoding of the samples.
+    format: SampleFormat,
+    /// What the command stream does after the startup sequence.
+    mode: Mode,
+    /// Synthetic signal replacing the samples.
+    signal: Option<Waveform>,
+}
+
+/// Timing of the command stream.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct Timing {
+    /// Number of 16MHz ticks between two commands.
+    pub timer_interval: u32,
+    /// How many commands to send for each frame. At least the channel count + 2.
+    pub stride: usize,
+    /// How many samples to read from each channel between interrupts.
+    pub frames_per_buffer: usize,
+}
+
+impl Timing {
+    /// Find the timing that comes closest to the requested sample rate per channel.
+    ///
+    /// Every stride that fits the channels is tried with the two nearest timer intervals
+    /// and the combination with the smallest rate error wins.
+    /// On a tie the shorter stride is preferred.
+    /// The number of frames per buffer is chosen so that an interrupt happens about every 20ms.
+    pub fn solve(sample_rate: u32, channel_count: usize) -> Result<Self, Error> {
+        if sample_rate == 0 {
+            return Err(Error::UnsupportedSampleRate);
+        }
+        let rate = sample_rate as u64;
+        let frequency = TIMER_FREQUENCY as u64;
+        // Best interval, stride and absolute error of `frequency - rate * period`.
+        let mut best: Option<(u64, usize, u64)> = None;
+        for stride in channel_count + 2..=MAX_STRIDE {
+            let ideal = frequency / (rate * stride as u64);
+            for interval in [ideal, ideal + 1] {
+                if interval < MIN_TIMER_INTERVAL as u64 || interval > MAX_TIMER_INTERVAL as u64 {
+                    continue;
+                }
+                let period = interval * stride as u64;
+                let error = frequency.abs_diff(rate * period);
+                // Compare the rate errors `error / period` without dividing.
+                let better = match best {
+                    Some((i, s, e)) => error * i * (s as u64) < e * period,
+                    None => true,
+                };
+                if better {
+                    best = Some((interval, stride, error));
+                }
+            }
+        }
+        let (timer_interval, stride, _) = best.ok_or(Error::UnsupportedSampleRate)?;
+        let period = timer_interval as u32 * stride as u32;
+        let min_frames = (MIN_BUFFER_SIZE + stride - 1) / stride;
+        let max_frames = MAX_BUFFER_SIZE / stride;
+        let frames_per_buffer =
+            (((BUFFER_PERIOD + period / 2) / period) as usize).clamp(min_frames, max_frames);
+        Ok(Self {
+            timer_interval: timer_interval as u32,
+            stride,
+            frames_per_buffer,
+        })
+    }
+    /// Change the number of frames per buffer to a multiple of `period` frames,
+    /// staying within the buffer size limits.
+    /// Returns `None` if no multiple fits into a buffer.
+    fn repeat_every(self, period: usize) -> Option<Self> {
+        let min_frames = (MIN_BUFFER_SIZE + self.stride - 1) / self.stride;
+        let max_frames = MAX_BUFFER_SIZE / self.stride;
+        let repeats = (self.frames_per_buffer / period).max((min_frames + period - 1) / period);
+        let frames_per_buffer = repeats * period;
+        (frames_per_buffer <= max_frames).then_some(Self {
+            frames_per_buffer,
+            ..self
+        })
+    }
+    /// Number of 16MHz ticks between two samples of the same channel.
+    pub fn sample_period(&self) -> u32 {
+        self.timer_interval * self.stride as u32
+    }
+    /// The exact sample rate per channel in Hz.
+    pub fn sample_rate(&self) -> f32 {
+        TIMER_FREQUENCY as f32 / self.sample_period() as f32
+    }
+    /// Size of one full buffer between interrupts.
+    fn buffer_size(&self) -> usize {
+        self.frames_per_buffer * self.stride
+    }
+}
+
+/// Errors reported by the driver.
+#[derive(Debug, defmt::Format)]
+pub enum Error {
+    /// The channel mask does not select any channel.
+    NoChannels,
+    /// The channel mask selects more than [`MAX_CHANNELS`] channels.
+    TooManyChannels,
+    /// A rate divisor is not a power of two up to [`MAX_DIVISOR`].
+    ///
+    /// [`MAX_DIVISOR`]: rhd2000_protocol::MAX_DIVISOR
+    InvalidDivisor,
+    /// The sample rate cannot be reached with the selected channels.
+    UnsupportedSampleRate,
+    /// The lower cutoff is not positive or not below the upper cutoff.
+    InvalidBandwidth,
+    /// The DSP cutoff is not positive.
+    InvalidDspCutoff,
+    /// The DSP offset removal is enabled together with rate divisors.
+    /// The chip runs the filter at the rate of each channel, so its cutoff would differ
+    /// between channels with different divisors.
+    DspWithDivisors,
+    /// The impedance test frequency cannot be generated at the sample rate.
+    UnsupportedTestFrequency,
+    /// The chip does not respond with its company ID.
+    ChipNotFound,
+    /// The chip is not a known RHD2000 chip, or its ROM does not match the chip ID.
+    /// Contains the chip ID that was read.
+    UnknownChip(u8),
+    /// The chip is a known RHD2000 chip that the driver does not support.
+    UnsupportedChip(Variant),
+    /// The channel mask selects channels the chip does not have.
+    UnsupportedChannels,
+    /// A register read back a different value than was written.
+    RegisterMismatch {
+        /// The register number.
+        register: u8,
+        /// The value written to the register.
+        written: u8,
+        /// The response to reading the register.
+        read: u16,
+    },
+}
+
+impl From<ScheduleError> for Error {
+    fn from(e: ScheduleError) -> Self {
+        match e {
+            ScheduleError::InvalidDivisor => Self::InvalidDivisor,
+            ScheduleError::TooManyLanes => Self::TooManyChannels,
+            ScheduleError::NoChannels => Self::NoChannels,
+        }
+    }
+}
 
 /// A handle for the RHD2216 ADC.
 pub struct RHD2216<'d> {
@@ -63,15 +368,81 @@ pub struct RHD2216<'d> {
     _spi: PeripheralRef<'d, peripherals::SPI3>,
 }
 
+/// Health of the command stream during one buffer.
+#[derive(Clone, Copy, Debug, Default, defmt::Format)]
+pub struct Status {
+    /// Number of responses to register commands that differ from the expected value.
+    pub bad_responses: u16,
+    /// Number of words received into the overflow space before the buffers were swapped.
+    pub overflow_words: u16,
+    /// Number of times the buffer swap before this packet missed the safe window
+    /// and had to be retried after the next command.
+    pub swap_retries: u16,
+    /// Number of buffer swaps since the start of the command stream at which the DMA pointers
+    /// did not match the transaction counter, meaning that a command slot was skipped or repeated.
+    /// Should always be 0.
+    pub slot_errors: u32,
+    /// Number of frames dropped before this packet because the receiver was too slow.
+    pub lost_frames: u32,
+    /// The chip was re-initialised after this packet.
+    /// The next packet is the first one of a new command stream.
+    pub restarted: bool,
+    /// Frames affected by a recalibration or fast settle.
+    pub marker: Option<Marker>,
+}
+
+impl Status {
+    /// Status flags for the data format.
+    /// Bit 0 is set if there were bad responses, bit 1 if frames were lost,
+    /// bit 2 if the chip was re-initialised after this packet, bit 3 if frames
+    /// are affected by a recalibration and bit 4 if frames are affected by a fast settle.
+    pub fn flags(&self) -> u8 {
+        let operation = self.marker.map(|m| m.operation);
+        (self.bad_responses != 0) as u8
+            | ((self.lost_frames != 0) as u8) << 1
+            | (self.restarted as u8) << 2
+            | ((operation == Some(Operation::Recalibrate)) as u8) << 3
+            | ((operation == Some(Operation::FastSettle)) as u8) << 4
+    }
+}
+
 /// A packet of data from the ADC
 #[derive(Debug)]
 pub struct Data {
     /// Number of channels.
     pub channels: usize,
+    /// Mask of the recorded amplifier channels.
+    pub channel_mask: u64,
     /// Number of the data packet. If a number is missing, it means you missed a packet.
     pub sequence_number: usize,
+    /// Index of the first frame of this packet since the start of the command stream.
+    /// The frames of lost packets are counted as well.
+    pub first_frame: u64,
+    /// Time in µs since boot at which the first frame of this packet was converted.
+    pub timestamp: u64,
+    /// Number of 16MHz ticks between two frames.
+    /// A channel with the rate divisor `d` is sampled every `d` frames.
+    pub sample_period: u32,
+    /// Encoding of the samples.
+    pub format: SampleFormat,
+    /// Health of the command stream while the samples were recorded.
+    pub status: Status,
+    /// Auxiliary measurements. Only available in acquisition mode
+    /// and if no operation was injected over the measurements.
+    pub aux: Option<AuxData>,
     /// Interleaved sample data.
-    pub frames: Vec<u16>,
+    /// Each frame holds the samples of the channels converted in it in the lane order of the
+    /// [`Schedule`]. Without rate divisors these are all channels in ascending order.
+    /// Every packet holds whole cycles of the schedule.
+    pub frames: Frames,
+}
+
+impl Data {
+    /// Flags for the data format.
+    /// Bits 0 to 4 are the [`Status::flags`], bit 5 is set for two's complement samples.
+    pub fn flags(&self) -> u8 {
+        self.status.flags() | ((self.format == SampleFormat::TwosComplement) as u8) << 5
+    }
 }
 
 /// Get the register block for SPIM3.
@@ -84,32 +455,30 @@ fn timer1_registers() -> &'static pac::timer2::RegisterBlock {
     unsafe { &*pac::TIMER1::ptr() }
 }
 
+/// Enable the CC1 interrupt for TIMER1. It fires right after every transaction.
+fn timer1_enable_cc1_isr() {
+    let r = timer1_registers();
+    r.intenset.write(|w| w.compare1().set());
+}
+
+/// Disable the CC1 interrupt for TIMER1.
+fn timer1_disable_cc1_isr() {
+    let r = timer1_registers();
+    r.intenclr.write(|w| w.compare1().clear());
+}
+
 /// Get the register block for TIMER2.
 fn timer2_registers() -> &'static pac::timer2::RegisterBlock {
     unsafe { &*pac::TIMER2::ptr() }
 }
 
-// Functions to generate commands as u16.
-// Most of these intentionally use LE byte order with swapped bytes.
-/// Command to convert channel `c`.
-const fn convert_channel(c: u8) -> u16 {
-    (c as u16).to_le()
-}
-/// Command to read register `r`.
-const fn read_register(r: u8) -> u16 {
-    (r as u16 | 192).to_le()
-}
-/// Command to write register `r` with value `d`.
-const fn write_register(r: u8, d: u8) -> u16 {
-    (((d as u16) << 8) | (r as u16) | 128).to_le()
-}
-/// Command to start the calibration sequence.
-const fn start_calibration() -> u16 {
-    0b01010101u16
-}
-/// Dummy command. Reads register 40. Should contain the fixed value `b'I'`.
-const fn dummy_command() -> u16 {
-    read_register(40)
+/// What the command stream does after the startup sequence.
+#[derive(Clone, Copy)]
+enum Mode {
+    /// Convert all active channels.
+    Acquisition,
+    /// Drive the impedance test DAC and convert the channel under test.
+    Impedance(ImpedanceTest),
 }
 
 /// State in which the ADC is.
@@ -117,7 +486,7 @@ const fn dummy_command() -> u16 {
 enum State {
     /// The ADC is stopped.
     Off,
-    /// The ADC is executing the calibration sequence.
+    /// The ADC is executing the calibration sequence. Receiving into buffer `rx1`.
     Starting,
     /// Receiving into buffer `rx1`.
     Rx1,
@@ -138,6 +507,40 @@ struct SpiBuffers {
     /// Sequence number for the next packet.
     /// Can be used to detect dropped packets.
     sequence_number: usize,
+    /// Index of the next frame to be sent since the start of the command stream.
+    frame_index: u64,
+    /// Mask of the active channels.
+    channel_mask: u64,
+    /// Number of active channels.
+    channel_count: usize,
+    /// Order of the conversions.
+    schedule: Schedule,
+    /// Timing of the command stream.
+    timing: Timing,
+    /// Encoding of the samples.
+    format: SampleFormat,
+    /// Register values written by the startup sequence.
+    registers: RegisterMap,
+    /// Number of frames dropped since the last packet that was sent.
+    lost_frames: u32,
+    /// What the command stream does after the startup sequence.
+    mode: Mode,
+    /// A recalibration has been requested.
+    recalibrate: bool,
+    /// A fast settle has been requested. Contains the duration in ms.
+    fast_settle: Option<u32>,
+    /// Number of buffers until the fast settle ends.
+    settle_buffers: usize,
+    /// Slots of the command buffer changed by an operation.
+    injected: Option<Range<usize>>,
+    /// Frames of the buffer being transmitted that are affected by an operation.
+    marker: Option<Marker>,
+    /// State of the buffer swap.
+    swap: Swap,
+    /// Number of buffer swaps at which a command slot was skipped or repeated.
+    slot_errors: u32,
+    /// Timing statistics of the command stream.
+    counters: Counters,
 }
 
 /// Static buffer space protected by a mutex.
@@ -147,9 +550,32 @@ static SPI_BUFFERS: Mutex<RefCell<SpiBuffers>> = Mutex::new(RefCell::new(SpiBuff
     rx2: [0u16; TOTAL_BUFFER],
     state: State::Off,
     sequence_number: 0,
+    frame_index: 0,
+    channel_mask: 0,
+    channel_count: 0,
+    schedule: Schedule::empty(),
+    timing: Timing {
+        timer_interval: 0,
+        stride: 0,
+        frames_per_buffer: 0,
+    },
+    format: SampleFormat::OffsetBinary,
+    registers: RegisterMap::new(),
+    lost_frames: 0,
+    mode: Mode::Acquisition,
+    recalibrate: false,
+    fast_settle: None,
+    settle_buffers: 0,
+    injected: None,
+    marker: None,
+    swap: Swap::new(),
+    slot_errors: 0,
+    counters: Counters::new(),
 }));
 /// Channel for passing the data from the interrupt to the main thread.
 static CHANNEL: Channel<CriticalSectionRawMutex, Data, 16> = Channel::new();
+/// Signal for passing the result of the startup sequence to the main thread.
+static STARTUP: Signal<CriticalSectionRawMutex, Result<ChipInfo, Error>> = Signal::new();
 
 impl SpiBuffers {
     /// Get the address of the TX buffer.
@@ -164,65 +590,81 @@ impl SpiBuffers {
     fn rx2_address(&self) -> u32 {
         &self.rx2 as *const _ as u32
     }
-    /// Fill the buffer b with startup commands.
-    /// Generates a sequence of commands that sets all registers and then starts a calibration.
-    fn fill_startup_commands(b: &mut [u16]) {
-        for (i, v) in b.iter_mut().enumerate() {
-            *v = match i {
-                // Write all the registers
-                10 => write_register(0, 0b11011110),
-                11 => write_register(1, 8),
-                12 => write_register(2, 32),
-                13 => write_register(3, 0),
-                14 => write_register(4, 0),
-                15 => write_register(5, 0),
-                16 => write_register(6, 0),
-                17 => write_register(7, 0),
-                // Upper Cutoff: 3kHz
-                /*18 => write_register(8, 3),
-                19 => write_register(9, 1),
-                20 => write_register(10, 13),
-                21 => write_register(11, 1),*/
-                // Upper Cutoff: 1kHz
-                18 => write_register(8, 46),
-                19 => write_register(9, 2),
-                20 => write_register(10, 30),
-                21 => write_register(11, 3),
-                // Lower Cutoff: 1Hz
-                22 => write_register(12, 44),
-                23 => write_register(13, 6),
-                // Channel Mask
-                24 => write_register(14, (CHANNEL_MASK & 255) as u8),
-                25 => write_register(15, ((CHANNEL_MASK >> 8) & 255) as u8),
-                26 => write_register(16, ((CHANNEL_MASK >> 16) & 255) as u8),
-                27 => write_register(17, ((CHANNEL_MASK >> 24) & 255) as u8),
-                // Leave at least 100µs before calibration starts
-                200 => start_calibration(),
-                _ => dummy_command(),
-            }
+    /// Register values for the startup sequence.
+    /// Only the amplifiers selected in the channel mask are powered up.
+    /// In impedance mode the test DAC is powered and connected to the channel under test,
+    /// in acquisition mode the auxiliary inputs and the supply and temperature sensors are enabled.
+    fn startup_registers(settings: &Settings) -> RegisterMap {
+        let twos_comp = settings.format == SampleFormat::TwosComplement;
+        let registers = RegisterMap::new()
+            .amplifiers(settings.channel_mask as u32)
+            .output(twos_comp, settings.dsp.map(|dsp| dsp.setting()));
+        let registers = settings.bandwidth.apply(registers);
+        match settings.mode {
+            Mode::Acquisition => registers.sensors(true),
+            Mode::Impedance(test) => test.apply(registers),
         }
     }
-    /// Fill the buffer with commands to read out all channels repeatedly.
-    fn fill_readout_commands(b: &mut [u16]) {
-        for (i, v) in b.iter_mut().enumerate() {
-            let n = i % STRIDE;
-            *v = if n < CHANNEL_COUNT {
-                convert_channel((n + SKIP_CHANNELS) as u8)
-            } else {
-                dummy_command()
-            }
-        }
+    /// Fill the buffer with commands to read out the channels of `schedule` repeatedly.
+    /// In impedance mode the slot after the channels updates the test DAC,
+    /// in acquisition mode the slots after the channels take the auxiliary measurements.
+    fn fill_readout_commands(
+        b: &mut [u16],
+        schedule: &Schedule,
+        timing: &Timing,
+        mode: Mode,
+        registers: &RegisterMap,
+    ) {
+        let aux = AuxSchedule::new(timing);
+        fill_readout_commands(b, schedule, timing.stride, |frame, spare| match mode {
+            Mode::Impedance(test) if spare == 0 => write_register(6, test.dac_value(frame)),
+            Mode::Acquisition => aux
+                .command(frame % timing.frames_per_buffer, spare, registers)
+                .unwrap_or_else(dummy_command),
+            _ => dummy_command(),
+        });
     }
     /// Setup the SPI buffers and DMA pointers.
-    unsafe fn setup(&mut self) {
+    /// The channel mask must select between 1 and [`MAX_CHANNELS`] channels
+    /// and the timing must have been solved for the lanes of the schedule.
+    unsafe fn setup(&mut self, settings: &Settings) {
         let r = spi_registers();
+        let Settings {
+            channel_mask,
+            timing,
+            mode,
+            ..
+        } = *settings;
         if self.state != State::Off {
             panic!("Trying to start RHD while it is already running.");
         }
-        Self::fill_startup_commands(&mut self.tx[0..BUFFER_SIZE]);
-        Self::fill_readout_commands(&mut self.tx[BUFFER_SIZE..]);
+        self.channel_mask = channel_mask;
+        self.channel_count = channel_mask.count_ones() as usize;
+        self.schedule = settings.schedule;
+        self.timing = timing;
+        let buffer_size = timing.buffer_size();
+        self.format = settings.format;
+        self.registers = Self::startup_registers(settings);
+        fill_startup_commands(&mut self.tx[0..buffer_size], &self.registers);
+        Self::fill_readout_commands(
+            &mut self.tx[buffer_size..],
+            &self.schedule,
+            &timing,
+            mode,
+            &self.registers,
+        );
         self.state = State::Starting;
         self.sequence_number = 0;
+        self.frame_index = 0;
+        self.lost_frames = 0;
+        self.mode = mode;
+        self.recalibrate = false;
+        self.fast_settle = None;
+        self.settle_buffers = 0;
+        self.injected = None;
+        self.marker = None;
+        self.swap = Swap::new();
+        self.slot_errors = 0;
         r.txd.ptr.write(|w| unsafe { w.bits(self.tx_address()) });
         r.txd.maxcnt.write(|w| unsafe { w.maxcnt().bits(2) });
         r.txd.list.write(|w| w.list().array_list());
@@ -230,101 +672,156 @@ impl SpiBuffers {
         r.rxd.maxcnt.write(|w| unsafe { w.maxcnt().bits(2) });
         r.rxd.list.write(|w| w.list().array_list());
     }
+    /// Send `commands` one after the other while the command stream is stopped.
+    /// Returns once the last transaction has ended.
+    /// Sets up the transfers itself, so it also works before the first [`setup`](Self::setup).
+    unsafe fn send_commands(&mut self, commands: &[u16]) {
+        let r = spi_registers();
+        self.tx[..commands.len()].copy_from_slice(commands);
+        r.txd.ptr.write(|w| w.bits(self.tx_address()));
+        r.txd.maxcnt.write(|w| w.maxcnt().bits(2));
+        r.txd.list.write(|w| w.list().array_list());
+        r.rxd.ptr.write(|w| w.bits(self.rx1_address()));
+        r.rxd.maxcnt.write(|w| w.maxcnt().bits(2));
+        r.rxd.list.write(|w| w.list().array_list());
+        for _ in commands {
+            r.events_end.reset();
+            r.tasks_start.write(|w| w.bits(1));
+            while r.events_end.read().bits() == 0 {}
+        }
+        r.events_end.reset();
+    }
     /// Update the buffer state and swap the RX buffers.
     /// Call this every time the interrupt runs.
     unsafe fn update(&mut self) {
-        let r = spi_registers();
+        let buffer_size = self.timing.buffer_size();
         match self.state {
             State::Off => {
                 // Should only happen if stop was called while interrupt was
                 // already signalled but not served yet.
             }
             State::Starting => {
-                self.state = State::Rx1;
-                Self::fill_readout_commands(&mut self.tx[0..BUFFER_SIZE]);
-                adjust_pointer(r.txd.ptr.as_ptr(), 0u32.wrapping_sub(OFFSET));
-                let n = adjust_pointer(r.rxd.ptr.as_ptr(), 0u32.wrapping_sub(OFFSET))
-                    .wrapping_sub(self.rx1_address()) as usize;
-                // Copy overflow
-                for i in 0..n {
-                    self.rx1[i] = self.rx1[BUFFER_SIZE + i];
-                }
+                // The startup buffer starts at the beginning of `rx1`.
+                STARTUP.signal(self_test::check_startup(
+                    &self.rx1[0..buffer_size],
+                    &self.registers,
+                ));
+                // The second buffer already holds the readout commands.
+                self.tx.copy_within(buffer_size..2 * buffer_size, 0);
+                self.request_swap(State::Rx2);
             }
             State::Rx1 => {
-                self.state = State::Rx2;
-                adjust_pointer(r.txd.ptr.as_ptr(), 0u32.wrapping_sub(OFFSET));
-                let offset = self
-                    .rx2_address()
-                    .wrapping_sub(self.rx1_address())
-                    .wrapping_sub(OFFSET);
-                let n = adjust_pointer(r.rxd.ptr.as_ptr(), offset).wrapping_sub(self.rx2_address())
-                    as usize;
-                // Copy overflow
-                for i in 0..n {
-                    self.rx2[i] = self.rx1[BUFFER_SIZE + i];
-                }
-                // Generate frame
-                let mut data = Data {
-                    channels: CHANNEL_COUNT,
-                    sequence_number: self.sequence_number,
-                    frames: Vec::<u16>::with_capacity(FRAMES_PER_BUFFER * CHANNEL_COUNT),
-                };
-                self.sequence_number = self.sequence_number.wrapping_add(1);
-                let ok = self.rx1[0].to_be() == b'I' as u16;
-                for f in 0..FRAMES_PER_BUFFER {
-                    for c in 0..CHANNEL_COUNT {
-                        data.frames.push(self.rx1[f * STRIDE + c + 2].to_be());
-                    }
+                let spill = self.swap.spill;
+                let latency = self.interrupt_latency();
+                self.counters.interrupt(latency);
+                let timestamp = self.buffer_start_time(latency);
+                if self.request_swap(State::Rx2) {
+                    self.send_data(State::Rx1, spill, timestamp);
+                    self.inject_operations();
+                } else {
+                    self.drop_buffer();
                 }
-                match CHANNEL.try_send(data) {
-                    Ok(_) => {
-                        if ok {
-                            info!("Ok")
-                        } else {
-                            warn!("Not responding")
-                        }
-                    }
-                    Err(_) => warn!("Frame lost!"),
-                };
             }
             State::Rx2 => {
-                self.state = State::Rx1;
-                adjust_pointer(r.txd.ptr.as_ptr(), 0u32.wrapping_sub(OFFSET));
-                let offset = self
-                    .rx1_address()
-                    .wrapping_sub(self.rx2_address())
-                    .wrapping_sub(OFFSET);
-                let n = adjust_pointer(r.rxd.ptr.as_ptr(), offset).wrapping_sub(self.rx1_address())
-                    as usize;
-                // Copy overflow
-                for i in 0..n {
-                    self.rx1[i] = self.rx2[BUFFER_SIZE + i];
+                let spill = self.swap.spill;
+                let latency = self.interrupt_latency();
+                self.counters.interrupt(latency);
+                let timestamp = self.buffer_start_time(latency);
+                if self.request_swap(State::Rx1) {
+                    self.send_data(State::Rx2, spill, timestamp);
+                    self.inject_operations();
+                } else {
+                    self.drop_buffer();
                 }
-                // Generate frame
-                let mut data = Data {
-                    channels: CHANNEL_COUNT,
-                    sequence_number: self.sequence_number,
-                    frames: Vec::<u16>::with_capacity(FRAMES_PER_BUFFER * CHANNEL_COUNT),
-                };
-                self.sequence_number = self.sequence_number.wrapping_add(1);
-                let ok = self.rx1[0].to_be() == b'I' as u16;
-                for f in 0..FRAMES_PER_BUFFER {
-                    for c in 0..CHANNEL_COUNT {
-                        data.frames.push(self.rx2[f * STRIDE + c + 2].to_be());
-                    }
+            }
+        }
+    }
+    /// Count the buffer that has just been completed as lost, because its responses were not
+    /// received into its buffer. The commands injected into it are kept for the next buffer.
+    fn drop_buffer(&mut self) {
+        let frames = self.timing.frames_per_buffer;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        self.frame_index += frames as u64;
+        self.lost_frames = self.lost_frames.saturating_add(frames as u32);
+    }
+    /// Generate a data packet from the buffer that has just been filled and send it to the main thread.
+    /// `spill` is the number of its words received into the overflow space of the other buffer
+    /// and `timestamp` the time its first command was sent.
+    fn send_data(&mut self, filled: State, spill: usize, timestamp: u64) {
+        self.counters.spill(spill);
+        let timing = self.timing;
+        let buffer_size = timing.buffer_size();
+        let rx = self.received(&filled, spill);
+        // The responses to injected commands are not known in advance.
+        let skip = self.injected.clone().unwrap_or(0..0);
+        let end = buffer_size - 2;
+        let resume = skip.end.min(end);
+        let bad_responses =
+            self_test::count_bad_responses(&self.tx, 0..skip.start, &rx, &self.registers)
+                + self_test::count_bad_responses(&self.tx, resume..end, &rx, &self.registers);
+        let aux = AuxSchedule::new(&timing);
+        let undisturbed = self
+            .injected
+            .as_ref()
+            .map_or(true, |r| r.start >= aux.frames() * timing.stride);
+        let aux = match self.mode {
+            Mode::Acquisition if undisturbed => {
+                let slot = self.schedule.lanes();
+                Some(aux.decode(|f| rx.response(f * timing.stride + slot)))
+            }
+            _ => None,
+        };
+        let sequence_number = self.sequence_number;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        let first_frame = self.frame_index;
+        self.frame_index += timing.frames_per_buffer as u64;
+        let marker = self.marker.take();
+        if bad_responses != 0 {
+            warn!("Not responding")
+        }
+        let Some(mut frames) = Frames::new() else {
+            warn!("Frame lost!");
+            self.lost_frames = self
+                .lost_frames
+                .saturating_add(timing.frames_per_buffer as u32);
+            return;
+        };
+        for f in 0..timing.frames_per_buffer {
+            for lane in 0..self.schedule.lanes() {
+                if self.schedule.channel(f, lane).is_some() {
+                    frames.push(rx.response(f * timing.stride + lane));
                 }
-                match CHANNEL.try_send(data) {
-                    Ok(_) => {
-                        if ok {
-                            info!("Ok")
-                        } else {
-                            warn!("Not responding")
-                        }
-                    }
-                    Err(_) => warn!("Frame lost!"),
-                };
             }
         }
+        let data = Data {
+            channels: self.channel_count,
+            channel_mask: self.channel_mask,
+            sequence_number,
+            first_frame,
+            timestamp,
+            sample_period: timing.sample_period(),
+            format: self.format,
+            status: Status {
+                bad_responses,
+                overflow_words: spill as u16,
+                swap_retries: self.swap.retries,
+                slot_errors: self.slot_errors,
+                lost_frames: self.lost_frames,
+                restarted: false,
+                marker,
+            },
+            aux,
+            frames,
+        };
+        match CHANNEL.try_send(data) {
+            Ok(_) => self.lost_frames = 0,
+            Err(_) => {
+                warn!("Frame lost!");
+                self.lost_frames = self
+                    .lost_frames
+                    .saturating_add(timing.frames_per_buffer as u32);
+            }
+        };
     }
 }
 
@@ -333,45 +830,6 @@ pub struct InterruptHandler {
     _phantom: PhantomData<peripherals::TIMER2>,
 }
 
-/// Adjust a pointer register in an atomic fashion.
-/// Returns the value the register has been set to.
-/// This is used to access the DMA pointers of the SPI interface.
-/// They should only be modified if there is currently no transaction
-/// happening and the timer is not close to overflowing.
-unsafe fn adjust_pointer(x: *mut u32, n: u32) -> u32 {
-    let r = timer1_registers();
-    let capture = r.tasks_capture[3].as_ptr() as u32;
-    let cc = r.cc[3].as_ptr() as u32;
-    let v: u32;
-    asm!(
-        "2:",
-        // Load the pointer
-        "ldrex {v}, [{x}]",
-        // Capture the timer value
-        "mov {t}, #1",
-        "str {t}, [{capture}]",
-        "ldr {t}, [{cc}]",
-        // Check for range
-        "cmp {t}, #25", // Lower bound
-        "blt 2b",
-        "cmp {t}, {upper}", // Upper bound
-        "bgt 2b",
-        // Adjust and store the pointer
-        "add {v}, {n}",
-        "strex {t}, {v}, [{x}]",
-        "cmp {t}, #0",
-        "bne 2b",
-        upper = in(reg) TIMER_INTERVAL - 20,
-        x = in(reg) x,
-        n = in(reg) n,
-        capture = in(reg) capture,
-        cc = in(reg) cc,
-        v = out(reg) v,
-        t = out(reg) _
-    );
-    v
-}
-
 /// Get the SPI start task for use with the PPI.
 fn spi_start_task() -> Task<'static> {
     let r = spi_registers();
@@ -384,30 +842,33 @@ fn spi_end_event() -> Event<'static> {
     unsafe { Event::new_unchecked(NonNull::new_unchecked(r.events_end.as_ptr())) }
 }
 
-impl interrupt::typelevel::Handler<interrupt::typelevel::TIMER2> for InterruptHandler {
+impl interrupt::typelevel::Handler<interrupt::typelevel::TIMER1> for InterruptHandler {
     unsafe fn on_interrupt() {
-        let mut pin = embassy_nrf::gpio::Output::new(
-            embassy_nrf::peripherals::P0_21::steal(),
-            embassy_nrf::gpio::Level::High,
-            embassy_nrf::gpio::OutputDrive::Standard,
-        );
+        let r = timer1_registers();
+        if r.events_compare[1].read().bits() != 0 {
+            r.events_compare[1].write(|w| w.events_compare().clear_bit());
+            with_buffers(|b| b.retry_swap());
+        }
+    }
+}
 
+impl interrupt::typelevel::Handler<interrupt::typelevel::TIMER2> for InterruptHandler {
+    unsafe fn on_interrupt() {
         let r = timer2_registers();
         if r.events_compare[0].read().bits() != 0 {
             r.events_compare[0].write(|w| w.events_compare().clear_bit());
-            critical_section::with(|cs| {
-                SPI_BUFFERS.borrow_ref_mut(cs).update();
-            });
+            with_buffers(|b| b.update());
         }
-
-        pin.set_low();
     }
 }
 
 /// Enable the CC0 interrupt for TIMER2.
+/// The TIMER1 interrupt for retrying the buffer swap gets enabled at the same priority.
 fn timer2_enable_cc0_isr() {
+    interrupt::typelevel::TIMER1::set_priority(interrupt::Priority::P2);
     interrupt::typelevel::TIMER2::set_priority(interrupt::Priority::P2);
     unsafe {
+        interrupt::typelevel::TIMER1::enable();
         interrupt::typelevel::TIMER2::enable();
     }
     let r = timer2_registers();
@@ -420,11 +881,31 @@ fn timer2_disable_cc0_isr() {
     r.intenset.write(|w| w.compare0().clear_bit());
 }
 
+/// Configure `pin` as an output with the given level.
+/// The SPI overrides the level while it is enabled.
+fn configure_output(pin: &AnyPin, high: bool) {
+    let r = unsafe {
+        &*match pin.port() {
+            Port::Port0 => pac::P0::ptr(),
+            Port::Port1 => pac::P1::ptr(),
+        }
+    };
+    let bit = 1 << pin.pin();
+    if high {
+        r.outset.write(|w| unsafe { w.bits(bit) });
+    } else {
+        r.outclr.write(|w| unsafe { w.bits(bit) });
+    }
+    r.pin_cnf[pin.pin() as usize].write(|w| w.dir().output().input().disconnect());
+}
+
 impl<'d> RHD2216<'d> {
     /// Create a handle to the RHD2216.
     #[allow(clippy::too_many_arguments)]
     pub fn new(
-        _irq: impl interrupt::typelevel::Binding<interrupt::typelevel::TIMER2, InterruptHandler> + 'd,
+        _irq: impl interrupt::typelevel::Binding<interrupt::typelevel::TIMER1, InterruptHandler>
+            + interrupt::typelevel::Binding<interrupt::typelevel::TIMER2, InterruptHandler>
+            + 'd,
         spi: peripherals::SPI3,
         timer1: peripherals::TIMER1,
         timer2: peripherals::TIMER2,
@@ -452,11 +933,17 @@ impl<'d> RHD2216<'d> {
             _spi: spi,
         };
         rhd.spi_setup();
+        rhd.power_down();
+        stats::enable_cycle_counter();
         rhd
     }
     /// Setup the SPI registers and configure the IO pins.
+    /// The pins keep their idle levels while the SPI is disabled.
     fn spi_setup(&mut self) {
         let r = spi_registers();
+        configure_output(&self.cs, true);
+        configure_output(&self.clk, false);
+        configure_output(&self.mosi, false);
         r.psel.csn.write(|w| unsafe {
             w.pin()
                 .bits(self.cs.pin())
@@ -493,36 +980,77 @@ impl<'d> RHD2216<'d> {
         r.frequency.write(|w| w.frequency().m16());
         r.enable.write(|w| w.enable().enabled());
     }
-    /// Start the ADC.
-    pub fn start<'a>(&'a mut self) -> Running<'a, 'd> {
-        critical_section::with(|cs| unsafe {
-            SPI_BUFFERS.borrow_ref_mut(cs).setup();
-        });
+    /// Start the command stream and wait for the result of the startup sequence.
+    /// The command stream is stopped again if the startup sequence failed.
+    /// The arguments must have been checked before.
+    async fn launch(&mut self, settings: &Settings) -> Result<ChipInfo, Error> {
+        let timing = settings.timing;
+        STARTUP.reset();
+        spi_registers().enable.write(|w| w.enable().enabled());
+        with_buffers(|b| unsafe { b.setup(settings) });
         self.timer1.clear();
         self.timer2.clear();
         self.timer1.cc(0).short_compare_clear();
         self.timer2.cc(0).short_compare_clear();
-        self.timer1.cc(0).write(TIMER_INTERVAL as u32);
-        self.timer2.cc(0).write(BUFFER_SIZE as u32);
+        self.timer1.cc(0).write(timing.timer_interval);
+        self.timer1.cc(1).write(SAFE_START);
+        self.timer2.cc(0).write(timing.buffer_size() as u32);
         timer2_enable_cc0_isr();
         self.ppi1.enable();
         self.ppi2.enable();
         self.timer1.set_frequency(timer::Frequency::F16MHz);
+        self.timer2.start();
         self.timer1.start();
-        Running { rhd: self }
+        let result = match STARTUP.wait().await {
+            Ok(chip_info) if settings.channel_mask & !chip_info.variant.channel_mask() != 0 => {
+                Err(Error::UnsupportedChannels)
+            }
+            result => result,
+        };
+        if result.is_err() {
+            self.stop();
+        }
+        result
     }
-    /// Stop the ADC.
+    /// Start the command stream and return the token for the running ADC.
+    /// The arguments must have been checked before.
+    async fn run(&mut self, settings: Settings) -> Result<Running<'_, 'd>, Error> {
+        let chip_info = self.launch(&settings).await?;
+        with_buffers(|b| b.counters = Counters::new());
+        Ok(Running {
+            rhd: self,
+            settings,
+            chip_info,
+            failures: 0,
+        })
+    }
+    /// Stop the ADC and put it into its low-power state.
     fn stop(&mut self) {
-        critical_section::with(|cs| {
-            let mut x = SPI_BUFFERS.borrow_ref_mut(cs);
+        with_buffers(|x| {
             x.state = State::Off;
             self.timer1.stop();
+            self.timer2.stop();
+            timer1_disable_cc1_isr();
             timer2_disable_cc0_isr();
             self.ppi1.disable();
             self.ppi2.disable();
         });
         // Drain channel.
         while CHANNEL.try_receive().is_ok() {}
+        self.power_down();
+    }
+    /// Switch off the amplifiers and the ADC biases of the chip and disable the SPI.
+    /// The command stream must be stopped. The next start restores the full configuration.
+    /// The SPI may already be disabled by an earlier stop.
+    fn power_down(&mut self) {
+        spi_registers().enable.write(|w| w.enable().enabled());
+        // Not recorded in the statistics, nothing is waiting for the buffers while stopped.
+        critical_section::with(|cs| unsafe {
+            let mut x = SPI_BUFFERS.borrow_ref_mut(cs);
+            let commands = power_down_commands(&x.registers);
+            x.send_commands(&commands);
+        });
+        spi_registers().enable.write(|w| w.enable().disabled());
     }
     /// Wait until a data packet from the ADC is ready.
     fn read(&mut self) -> impl Future<Output = Data> {
@@ -530,17 +1058,201 @@ impl<'d> RHD2216<'d> {
     }
 }
 
+impl<'d> Rhd2000 for RHD2216<'d> {
+    type Running<'a>
+        = Running<'a, 'd>
+    where
+        Self: 'a;
+
+    /// Start the ADC with the given configuration.
+    /// Waits until the startup sequence has identified the chip and verified the registers.
+    async fn start(&mut self, config: &Config) -> Result<Running<'_, 'd>, Error> {
+        match config.channel_mask.count_ones() as usize {
+            0 => return Err(Error::NoChannels),
+            n if n > MAX_CHANNELS => return Err(Error::TooManyChannels),
+            _ => {}
+        };
+        let schedule = Schedule::new(
+            (0..MAX_AMPLIFIERS)
+                .filter(|&c| config.channel_mask & (1u64 << c) != 0)
+                .map(|c| (c as u8, config.divisors[c] as usize)),
+        )?;
+        // Every buffer has to hold whole cycles of the schedule.
+        let timing = Timing::solve(config.sample_rate, schedule.lanes())?
+            .repeat_every(schedule.cycle())
+            .ok_or(Error::UnsupportedSampleRate)?;
+        let bandwidth = Bandwidth::new(config.upper_bandwidth, config.lower_bandwidth)
+            .ok_or(Error::InvalidBandwidth)?;
+        let dsp = match config.dsp_cutoff {
+            Some(_) if schedule.cycle() > 1 => return Err(Error::DspWithDivisors),
+            Some(cutoff) => {
+                Some(DspFilter::new(cutoff, timing.sample_rate()).ok_or(Error::InvalidDspCutoff)?)
+            }
+            None => None,
+        };
+        self.run(Settings {
+            channel_mask: config.channel_mask,
+            schedule,
+            timing,
+            bandwidth,
+            dsp,
+            format: config.format,
+            mode: Mode::Acquisition,
+            signal: config.signal,
+        })
+        .await
+    }
+    /// Measure the impedance of the electrodes selected in the configuration.
+    /// Each channel is measured on its own, so this takes a few hundred milliseconds per channel.
+    async fn measure_impedance(
+        &mut self,
+        config: &Config,
+        impedance: &ImpedanceConfig,
+    ) -> Result<ImpedanceReport, Error> {
+        if config.channel_mask == 0 {
+            return Err(Error::NoChannels);
+        }
+        // The bandwidth of the recording would distort the test signal near its cutoffs.
+        let bandwidth = Bandwidth::new(impedance.upper_bandwidth, impedance.lower_bandwidth)
+            .ok_or(Error::InvalidBandwidth)?;
+        // The DAC update takes the slot of a second channel.
+        let timing = Timing::solve(impedance.sample_rate, 2)?;
+        let period = impedance::test_period(impedance.frequency, &timing)
+            .ok_or(Error::UnsupportedTestFrequency)?;
+        // The command buffer is repeated, so it has to hold whole periods of the test signal.
+        let timing = timing
+            .repeat_every(period)
+            .ok_or(Error::UnsupportedTestFrequency)?;
+        let frequency = timing.sample_rate() / period as f32;
+        let mut report = ImpedanceReport {
+            channel_mask: config.channel_mask,
+            frequency,
+            channels: [Impedance::default(); MAX_AMPLIFIERS],
+        };
+        for c in 0..MAX_AMPLIFIERS {
+            if config.channel_mask & (1u64 << c) == 0 {
+                continue;
+            }
+            let test = ImpedanceTest {
+                channel: c as u8,
+                period,
+                scale: impedance.scale,
+            };
+            let mut correlator = Correlator::new(test);
+            // The correlator expects offset binary samples without offset removal.
+            let mut running = self
+                .run(Settings {
+                    channel_mask: 1u64 << c,
+                    schedule: Schedule::uniform(&[c as u8])?,
+                    timing,
+                    bandwidth,
+                    dsp: None,
+                    format: SampleFormat::OffsetBinary,
+                    mode: Mode::Impedance(test),
+                    signal: None,
+                })
+                .await?;
+            // Let the amplifier settle after the calibration.
+            running.read().await?;
+            while correlator.count() < impedance.periods * period {
+                let data = running.read().await?;
+                if data.status.restarted {
+                    // Start over with the new command stream.
+                    correlator = Correlator::new(test);
+                    running.read().await?;
+                    continue;
+                }
+                for &v in &data.frames {
+                    correlator.add(v);
+                }
+            }
+            report.channels[c] = correlator.impedance(frequency, timing.stride);
+        }
+        Ok(report)
+    }
+}
+
 /// Token for a running RHD.
 /// The RHD automatically gets stopped when this token gets dropped.
 pub struct Running<'a, 'd> {
     /// A reference to the RHD.
     rhd: &'a mut RHD2216<'d>,
+    /// Settings of the recording session.
+    settings: Settings,
+    /// Information about the chip.
+    chip_info: ChipInfo,
+    /// Number of consecutive packets with bad responses.
+    failures: usize,
 }
 
-impl<'a, 'd> Running<'a, 'd> {
-    /// Wait until a data packet from the ADC is ready.
-    pub fn read(&mut self) -> impl Future<Output = Data> {
-        self.rhd.read()
+impl<'a, 'd> Acquisition for Running<'a, 'd> {
+    /// Re-initialises the chip after [`REINIT_AFTER`] consecutive packets with bad responses.
+    /// If the chip does not respond, the re-initialisation is repeated up to
+    /// [`REINIT_ATTEMPTS`] times before the error is returned.
+    async fn read(&mut self) -> Result<Data, Error> {
+        let mut data = self.rhd.read().await;
+        if let Some(waveform) = self.settings.signal {
+            synthetic::replace_samples(&mut data, &self.settings.schedule, &waveform);
+        }
+        if data.status.bad_responses == 0 {
+            self.failures = 0;
+            return Ok(data);
+        }
+        self.failures += 1;
+        if self.failures < REINIT_AFTER {
+            return Ok(data);
+        }
+        warn!("Re-initialising the RHD");
+        self.failures = 0;
+        self.rhd.stop();
+        let mut attempts = 0;
+        self.chip_info = loop {
+            match self.rhd.launch(&self.settings).await {
+                Ok(chip_info) => break chip_info,
+                Err(e) => {
+                    warn!("Re-initialisation failed: {}", e);
+                    attempts += 1;
+                    if attempts == REINIT_ATTEMPTS {
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        data.status.restarted = true;
+        Ok(data)
+    }
+    fn timing(&self) -> Timing {
+        self.settings.timing
+    }
+    fn bandwidth(&self) -> Bandwidth {
+        self.settings.bandwidth
+    }
+    fn dsp_filter(&self) -> Option<DspFilter> {
+        self.settings.dsp
+    }
+    fn chip_info(&self) -> ChipInfo {
+        self.chip_info
+    }
+    fn registers(&self) -> RegisterMap {
+        SpiBuffers::startup_registers(&self.settings)
+    }
+    fn channel_mask(&self) -> u64 {
+        self.settings.channel_mask
+    }
+    fn format(&self) -> SampleFormat {
+        self.settings.format
+    }
+    fn schedule(&self) -> Schedule {
+        self.settings.schedule
+    }
+    fn statistics(&mut self) -> Statistics {
+        with_buffers(|b| b.counters.take(&self.settings.timing))
+    }
+    fn recalibrate(&mut self) {
+        with_buffers(|b| b.recalibrate = true);
+    }
+    fn fast_settle(&mut self, duration_ms: u32) {
+        with_buffers(|b| b.fast_settle = Some(duration_ms));
     }
 }
 
@@ -0,0 +1,101 @@
+//! Auxiliary inputs, supply voltage sensor and temperature sensor.
+//!
+//! In acquisition mode the first two spare slots of each frame sample the auxiliary sources.
+//! One conversion is done per frame in the first spare slot, the second spare slot switches
+//! the temperature sensor. The schedule restarts with every buffer, so each packet contains
+//! one measurement of every source.
+//!
+//! The temperature is the difference of two conversions with different sensor settings.
+//! The sensor needs 100µs to settle after it has been switched.
+
+use rhd2000_protocol::{RegisterMap, AUX_LSB, SUPPLY_LSB, TEMPERATURE_SCALE};
+
+use super::{convert_channel, Timing, TIMER_FREQUENCY};
+
+/// Channel number of the AUXIN1 pin. AUXIN2 and AUXIN3 follow.
+const AUX_INPUT: u8 = 32;
+/// Channel number of the supply voltage sensor.
+const SUPPLY_SENSOR: u8 = 48;
+/// Channel number of the temperature sensor.
+const TEMPERATURE_SENSOR: u8 = 49;
+/// Number of 16MHz ticks the temperature sensor needs to settle.
+const TEMPERATURE_SETTLE: u32 = TIMER_FREQUENCY / 10_000;
+/// Number of frames taken by the conversions of the inputs and the supply sensor.
+const VOLTAGE_FRAMES: usize = 4;
+
+/// Auxiliary measurements of one packet.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct AuxData {
+    /// Voltages of the pins AUXIN1 to AUXIN3 in V.
+    pub inputs: [f32; 3],
+    /// Supply voltage in V.
+    pub supply: f32,
+    /// Die temperature in °C.
+    /// `None` if the buffer is too short for the temperature measurement.
+    pub temperature: Option<f32>,
+}
+
+/// Frames of a buffer used for the auxiliary measurements.
+pub(super) struct AuxSchedule {
+    /// Frame of the first temperature conversion and the switch to the second setting.
+    first: usize,
+    /// Frame of the second temperature conversion.
+    second: usize,
+    /// Whether the temperature measurement fits into the buffer.
+    temperature: bool,
+}
+
+impl AuxSchedule {
+    /// Plan the measurements for the timing.
+    /// The result of the last frame arrives after the interrupt, so the last frame is not used.
+    pub fn new(timing: &Timing) -> Self {
+        let settle = ((TEMPERATURE_SETTLE + timing.sample_period() - 1) / timing.sample_period())
+            as usize
+            + 1;
+        let first = VOLTAGE_FRAMES.max(settle);
+        let second = first + settle;
+        Self {
+            first,
+            second,
+            temperature: second + 1 < timing.frames_per_buffer,
+        }
+    }
+    /// Number of frames at the start of the buffer that are used.
+    pub fn frames(&self) -> usize {
+        if self.temperature {
+            self.second + 1
+        } else {
+            VOLTAGE_FRAMES
+        }
+    }
+    /// Command for the spare slot `spare` in `frame`, if there is one.
+    /// The temperature sensor switches are set in register 3 of `registers`.
+    pub fn command(&self, frame: usize, spare: usize, registers: &RegisterMap) -> Option<u16> {
+        match (spare, frame) {
+            (0, f) if f < VOLTAGE_FRAMES - 1 => Some(convert_channel(AUX_INPUT + f as u8)),
+            (0, f) if f == VOLTAGE_FRAMES - 1 => Some(convert_channel(SUPPLY_SENSOR)),
+            (0, f) if self.temperature && (f == self.first || f == self.second) => {
+                Some(convert_channel(TEMPERATURE_SENSOR))
+            }
+            (1, 0) if self.temperature => {
+                Some(registers.temperature_switches(true, false).write_command(3))
+            }
+            (1, f) if self.temperature && f == self.first => {
+                Some(registers.temperature_switches(true, true).write_command(3))
+            }
+            _ => None,
+        }
+    }
+    /// Convert the results to physical units.
+    /// `result` returns the result of the conversion in the first spare slot of a frame.
+    pub fn decode(&self, result: impl Fn(usize) -> u16) -> AuxData {
+        AuxData {
+            inputs: [0, 1, 2].map(|f| result(f) as f32 * AUX_LSB),
+            supply: result(VOLTAGE_FRAMES - 1) as f32 * SUPPLY_LSB,
+            temperature: self.temperature.then(|| {
+                (result(self.second) as f32 - result(self.first) as f32) / TEMPERATURE_SCALE
+                    - 273.15
+            }),
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Calculation of the amplifier bandwidth registers.
+//!
+//! The upper cutoff is set by the two on-chip resistors RH1 and RH2, the lower cutoff by RL.
+//! Each resistor is a chain of DACs with fixed unit resistances.
+//! The formulas relating resistance and cutoff frequency are the fits from the RHD2000 datasheet.
+
+use libm::{log10, pow, sqrt};
+use rhd2000_protocol::RegisterMap;
+
+/// Base resistance of RH1 in Ω.
+const RH1_BASE: f64 = 2200.0;
+/// Resistance of one RH1 DAC1 step in Ω.
+const RH1_DAC1_UNIT: f64 = 600.0;
+/// Resistance of one RH1 DAC2 step in Ω.
+const RH1_DAC2_UNIT: f64 = 29400.0;
+/// Number of RH1 DAC1 steps.
+const RH1_DAC1_STEPS: u8 = 63;
+/// Number of RH1 DAC2 steps.
+const RH1_DAC2_STEPS: u8 = 31;
+
+/// Base resistance of RH2 in Ω.
+const RH2_BASE: f64 = 8700.0;
+/// Resistance of one RH2 DAC1 step in Ω.
+const RH2_DAC1_UNIT: f64 = 763.0;
+/// Resistance of one RH2 DAC2 step in Ω.
+const RH2_DAC2_UNIT: f64 = 38400.0;
+/// Number of RH2 DAC1 steps.
+const RH2_DAC1_STEPS: u8 = 63;
+/// Number of RH2 DAC2 steps.
+const RH2_DAC2_STEPS: u8 = 31;
+
+/// Base resistance of RL in Ω.
+const RL_BASE: f64 = 3500.0;
+/// Resistance of one RL DAC1 step in Ω.
+const RL_DAC1_UNIT: f64 = 175.0;
+/// Resistance of one RL DAC2 step in Ω.
+const RL_DAC2_UNIT: f64 = 12700.0;
+/// Resistance of the RL DAC3 step in Ω.
+const RL_DAC3_UNIT: f64 = 3000000.0;
+/// Number of RL DAC1 steps.
+const RL_DAC1_STEPS: u8 = 127;
+/// Number of RL DAC2 steps.
+const RL_DAC2_STEPS: u8 = 63;
+
+/// Highest upper cutoff the amplifiers support in Hz.
+pub const MAX_UPPER_BANDWIDTH: f32 = 30000.0;
+/// Highest lower cutoff the amplifiers support in Hz.
+pub const MAX_LOWER_BANDWIDTH: f32 = 1500.0;
+
+/// Resistance of RH1 in Ω for an upper cutoff of `f` Hz.
+fn rh1_from_upper_bandwidth(f: f64) -> f64 {
+    let x = log10(f);
+    0.9730 * pow(10.0, 8.0968 - 1.1892 * x + 0.04767 * x * x)
+}
+
+/// Resistance of RH2 in Ω for an upper cutoff of `f` Hz.
+fn rh2_from_upper_bandwidth(f: f64) -> f64 {
+    let x = log10(f);
+    1.0191 * pow(10.0, 8.1009 - 1.0821 * x + 0.03383 * x * x)
+}
+
+/// Resistance of RL in Ω for a lower cutoff of `f` Hz.
+fn rl_from_lower_bandwidth(f: f64) -> f64 {
+    let x = log10(f);
+    if f < 4.0 {
+        1.0061
+            * pow(
+                10.0,
+                4.9391 - 1.2088 * x + 0.5698 * x * x + 0.1442 * x * x * x,
+            )
+    } else {
+        1.0061 * pow(10.0, 4.7351 - 0.5916 * x + 0.08482 * x * x)
+    }
+}
+
+/// Invert one of the resistance formulas.
+/// All of them fall monotonically with the frequency, so a bisection over the
+/// logarithm of the frequency between 10mHz and 100kHz finds the cutoff.
+fn frequency_from_resistance(r: f64, resistance: fn(f64) -> f64) -> f64 {
+    let mut low = -2.0;
+    let mut high = 5.0;
+    for _ in 0..40 {
+        let mid = (low + high) / 2.0;
+        if resistance(pow(10.0, mid)) > r {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    pow(10.0, (low + high) / 2.0)
+}
+
+/// Choose the settings of a two stage resistor DAC closest to `target`.
+/// Returns the number of coarse and fine steps and the resulting resistance.
+fn two_stage_dac(
+    target: f64,
+    base: f64,
+    coarse_unit: f64,
+    coarse_steps: u8,
+    fine_unit: f64,
+    fine_steps: u8,
+) -> (u8, u8, f64) {
+    let mut r = base;
+    let mut coarse = 0;
+    let mut fine = 0;
+    for _ in 0..coarse_steps {
+        if r < target - (coarse_unit - fine_unit / 2.0) {
+            r += coarse_unit;
+            coarse += 1;
+        }
+    }
+    for _ in 0..fine_steps {
+        if r < target - fine_unit / 2.0 {
+            r += fine_unit;
+            fine += 1;
+        }
+    }
+    (coarse, fine, r)
+}
+
+/// Amplifier bandwidth settings for registers 8 to 13.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct Bandwidth {
+    rh1_dac1: u8,
+    rh1_dac2: u8,
+    rh2_dac1: u8,
+    rh2_dac2: u8,
+    rl_dac1: u8,
+    rl_dac2: u8,
+    rl_dac3: bool,
+    /// Realised upper cutoff in Hz.
+    upper: f32,
+    /// Realised lower cutoff in Hz.
+    lower: f32,
+}
+
+impl Bandwidth {
+    /// Calculate the DAC settings closest to the requested cutoffs in Hz.
+    /// Returns `None` if the cutoffs are not positive or the lower cutoff is not below the upper cutoff.
+    /// Cutoffs above [`MAX_UPPER_BANDWIDTH`] and [`MAX_LOWER_BANDWIDTH`] are limited to these values.
+    pub fn new(upper: f32, lower: f32) -> Option<Self> {
+        if !(lower > 0.0 && upper > lower) {
+            return None;
+        }
+        let upper = upper.min(MAX_UPPER_BANDWIDTH) as f64;
+        let lower = lower.min(MAX_LOWER_BANDWIDTH) as f64;
+
+        let (rh1_dac2, rh1_dac1, rh1) = two_stage_dac(
+            rh1_from_upper_bandwidth(upper),
+            RH1_BASE,
+            RH1_DAC2_UNIT,
+            RH1_DAC2_STEPS,
+            RH1_DAC1_UNIT,
+            RH1_DAC1_STEPS,
+        );
+        let (rh2_dac2, rh2_dac1, rh2) = two_stage_dac(
+            rh2_from_upper_bandwidth(upper),
+            RH2_BASE,
+            RH2_DAC2_UNIT,
+            RH2_DAC2_STEPS,
+            RH2_DAC1_UNIT,
+            RH2_DAC1_STEPS,
+        );
+        // The very low cutoffs need the large DAC3 resistor.
+        let rl_dac3 = lower < 0.15;
+        let rl_base = if rl_dac3 {
+            RL_BASE + RL_DAC3_UNIT
+        } else {
+            RL_BASE
+        };
+        let (rl_dac2, rl_dac1, rl) = two_stage_dac(
+            rl_from_lower_bandwidth(lower),
+            rl_base,
+            RL_DAC2_UNIT,
+            RL_DAC2_STEPS,
+            RL_DAC1_UNIT,
+            RL_DAC1_STEPS,
+        );
+
+        // Both resistors give a slightly different estimate, so take the geometric mean.
+        let upper = sqrt(
+            frequency_from_resistance(rh1, rh1_from_upper_bandwidth)
+                * frequency_from_resistance(rh2, rh2_from_upper_bandwidth),
+        );
+        let lower = frequency_from_resistance(rl, rl_from_lower_bandwidth);
+        Some(Self {
+            rh1_dac1,
+            rh1_dac2,
+            rh2_dac1,
+            rh2_dac2,
+            rl_dac1,
+            rl_dac2,
+            rl_dac3,
+            upper: upper as f32,
+            lower: lower as f32,
+        })
+    }
+    /// Realised upper cutoff in Hz.
+    pub fn upper(&self) -> f32 {
+        self.upper
+    }
+    /// Realised lower cutoff in Hz.
+    pub fn lower(&self) -> f32 {
+        self.lower
+    }
+    /// Set the bandwidth registers 8 to 13 of `registers`.
+    pub fn apply(&self, registers: RegisterMap) -> RegisterMap {
+        registers.bandwidth(
+            (self.rh1_dac1, self.rh1_dac2),
+            (self.rh2_dac1, self.rh2_dac2),
+            (self.rl_dac1, self.rl_dac2, self.rl_dac3),
+        )
+    }
+}
@@ -0,0 +1,52 @@
+//! On-chip DSP high-pass filter for offset removal.
+//!
+//! The filter is a first order high-pass running on the ADC results of each channel.
+//! Its cutoff is a fixed fraction of the sample rate, selected by a 4 bit setting `n`
+//! with `f_c = f_s * ln(2^n / (2^n - 1)) / 2π`.
+
+use core::f32::consts::PI;
+use libm::{fabsf, logf};
+
+/// Highest DSP cutoff setting.
+const MAX_SETTING: u8 = 15;
+
+/// Cutoff of the setting `n` as a fraction of the sample rate.
+fn cutoff_fraction(n: u8) -> f32 {
+    let x = (1u32 << n) as f32;
+    logf(x / (x - 1.0)) / (2.0 * PI)
+}
+
+/// Settings of the DSP high-pass filter.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct DspFilter {
+    /// Cutoff setting from 1 to 15.
+    setting: u8,
+    /// Realised cutoff in Hz.
+    cutoff: f32,
+}
+
+impl DspFilter {
+    /// Choose the setting closest to the requested cutoff in Hz at the given sample rate.
+    /// Returns `None` if the cutoff is not positive.
+    pub fn new(cutoff: f32, sample_rate: f32) -> Option<Self> {
+        if cutoff.is_nan() || cutoff <= 0.0 {
+            return None;
+        }
+        let distance = |n: u8| fabsf(logf(cutoff_fraction(n) * sample_rate / cutoff));
+        let setting = (1..=MAX_SETTING)
+            .min_by(|&a, &b| distance(a).total_cmp(&distance(b)))
+            .unwrap_or(1);
+        Some(Self {
+            setting,
+            cutoff: cutoff_fraction(setting) * sample_rate,
+        })
+    }
+    /// Realised cutoff in Hz.
+    pub fn cutoff(&self) -> f32 {
+        self.cutoff
+    }
+    /// Value of the cutoff field in register 4.
+    pub(super) fn setting(&self) -> u8 {
+        self.setting
+    }
+}
@@ -0,0 +1,72 @@
+//! Sample buffers handed from the interrupt to the main thread.
+//!
+//! The buffers come from a static pool, so neither the interrupt nor the main thread
+//! has to touch the heap while recording.
+
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+    ptr::NonNull,
+};
+use data_channel::Pool;
+
+use super::MAX_BUFFER_SIZE;
+
+/// Maximum number of samples in one packet.
+/// Every frame needs at least one command per sample.
+pub const MAX_SAMPLES: usize = MAX_BUFFER_SIZE;
+/// Number of sample buffers.
+/// Enough for a full data channel, one packet being sent and one being filled.
+const FRAME_BUFFERS: usize = 18;
+
+/// Static buffer space for the samples.
+static FRAME_POOL: Pool<[u16; MAX_SAMPLES], FRAME_BUFFERS> = Pool::new();
+
+/// Interleaved samples in a buffer from a static pool.
+/// The buffer is returned to the pool when this gets dropped.
+pub struct Frames {
+    ptr: NonNull<[u16; MAX_SAMPLES]>,
+    len: usize,
+}
+
+// The buffer is owned exclusively.
+unsafe impl Send for Frames {}
+
+impl Frames {
+    /// Take an empty buffer from the pool.
+    /// Returns `None` if all buffers are in use.
+    pub(super) fn new() -> Option<Self> {
+        FRAME_POOL.allocate().map(|ptr| Self { ptr, len: 0 })
+    }
+    /// Append a sample. Panics if the buffer is full.
+    pub(super) fn push(&mut self, sample: u16) {
+        assert!(self.len < MAX_SAMPLES);
+        unsafe { (*self.ptr.as_ptr())[self.len] = sample };
+        self.len += 1;
+    }
+}
+
+impl Deref for Frames {
+    type Target = [u16];
+    fn deref(&self) -> &Self::Target {
+        unsafe { &(*self.ptr.as_ptr())[..self.len] }
+    }
+}
+
+impl DerefMut for Frames {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut (*self.ptr.as_ptr())[..self.len] }
+    }
+}
+
+impl fmt::Debug for Frames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Drop for Frames {
+    fn drop(&mut self) {
+        unsafe { FRAME_POOL.free(self.ptr) }
+    }
+}
@@ -0,0 +1,222 @@
+//! Electrode impedance measurement.
+//!
+//! The impedance test DAC generates a sine wave which is coupled into the selected electrode
+//! through a small on-chip capacitor. The resulting current `I = 2πfCV` flows through the
+//! electrode and the voltage it develops is recorded by the amplifier of that channel.
+//! The complex amplitude of the recorded voltage at the test frequency gives the impedance.
+
+use core::f32::consts::PI;
+use libm::{atan2f, cosf, sinf, sqrtf};
+use rhd2000_protocol::{RegisterMap, AMPLIFIER_LSB, AMPLIFIER_ZERO};
+
+use super::{bandwidth::MAX_UPPER_BANDWIDTH, Timing, MAX_AMPLIFIERS};
+
+/// Voltage of one step of the impedance test DAC in V.
+const DAC_STEP: f32 = 1.225 / 256.0;
+/// Amplitude of the generated sine wave in DAC steps.
+const DAC_AMPLITUDE: f32 = 128.0;
+/// Minimum number of frames for one period of the test signal.
+const MIN_PERIOD: usize = 4;
+
+/// Capacitor used to inject the test current.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub enum ImpedanceScale {
+    /// 0.1pF, for high impedances.
+    Small,
+    /// 1pF.
+    Medium,
+    /// 10pF, for low impedances.
+    Large,
+}
+
+impl ImpedanceScale {
+    /// Capacitance in F.
+    fn capacitance(self) -> f32 {
+        match self {
+            Self::Small => 0.1e-12,
+            Self::Medium => 1.0e-12,
+            Self::Large => 10.0e-12,
+        }
+    }
+    /// Value of the Zcheck scale field in register 5.
+    fn bits(self) -> u8 {
+        match self {
+            Self::Small => 0b00,
+            Self::Medium => 0b01,
+            Self::Large => 0b11,
+        }
+    }
+}
+
+/// Configuration for an impedance measurement.
+#[derive(Clone, Debug, defmt::Format)]
+pub struct ImpedanceConfig {
+    /// Requested test frequency in Hz.
+    /// The realised frequency is reported in [`ImpedanceReport::frequency`].
+    pub frequency: f32,
+    /// Sample rate used during the measurement in Hz.
+    /// Must be at least four times the test frequency.
+    pub sample_rate: u32,
+    /// Capacitor used to inject the test current.
+    pub scale: ImpedanceScale,
+    /// Number of periods of the test signal to average over for each channel.
+    pub periods: usize,
+    /// Requested upper cutoff of the amplifiers during the measurement in Hz.
+    /// The amplifiers attenuate and delay the test signal near their cutoffs,
+    /// so both cutoffs should be far from the test frequency.
+    pub upper_bandwidth: f32,
+    /// Requested lower cutoff of the amplifiers during the measurement in Hz.
+    pub lower_bandwidth: f32,
+}
+
+impl From<data_channel::ImpedanceScale> for ImpedanceScale {
+    fn from(scale: data_channel::ImpedanceScale) -> Self {
+        match scale {
+            data_channel::ImpedanceScale::Small => Self::Small,
+            data_channel::ImpedanceScale::Medium => Self::Medium,
+            data_channel::ImpedanceScale::Large => Self::Large,
+        }
+    }
+}
+
+impl From<&data_channel::ImpedanceParams> for ImpedanceConfig {
+    /// The parameters of the host with the default sample rate and bandwidth.
+    fn from(params: &data_channel::ImpedanceParams) -> Self {
+        Self {
+            frequency: params.frequency,
+            scale: params.scale.into(),
+            periods: params.periods as usize,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for ImpedanceConfig {
+    fn default() -> Self {
+        Self {
+            frequency: 1000.0,
+            sample_rate: 20000,
+            scale: ImpedanceScale::Medium,
+            periods: 20,
+            upper_bandwidth: MAX_UPPER_BANDWIDTH,
+            lower_bandwidth: 1.0,
+        }
+    }
+}
+
+/// Impedance of a single electrode.
+#[derive(Clone, Copy, Debug, Default, defmt::Format)]
+pub struct Impedance {
+    /// Magnitude in Ω.
+    pub magnitude: f32,
+    /// Phase in degrees.
+    pub phase: f32,
+}
+
+/// Result of an impedance measurement.
+#[derive(Clone, Debug, defmt::Format)]
+pub struct ImpedanceReport {
+    /// Mask of the measured channels.
+    pub channel_mask: u64,
+    /// Realised test frequency in Hz.
+    pub frequency: f32,
+    /// Impedance of each amplifier channel. Only the channels in the mask are valid.
+    pub channels: [Impedance; MAX_AMPLIFIERS],
+}
+
+/// Settings of the impedance test DAC while measuring a single channel.
+#[derive(Clone, Copy)]
+pub(super) struct ImpedanceTest {
+    /// The channel connected to the DAC.
+    pub channel: u8,
+    /// Number of frames in one period of the test signal.
+    pub period: usize,
+    /// Capacitor used to inject the test current.
+    pub scale: ImpedanceScale,
+}
+
+impl ImpedanceTest {
+    /// Power the DAC and connect it to the channel under test.
+    pub fn apply(&self, registers: RegisterMap) -> RegisterMap {
+        registers.impedance_check(self.channel, self.scale.bits())
+    }
+    /// DAC value to output in `frame`.
+    pub fn dac_value(&self, frame: usize) -> u8 {
+        let phase = 2.0 * PI * (frame % self.period) as f32 / self.period as f32;
+        (DAC_AMPLITUDE + DAC_AMPLITUDE * sinf(phase) + 0.5).clamp(0.0, 255.0) as u8
+    }
+}
+
+/// Find the number of frames for one period of the test signal.
+/// Returns `None` if the test frequency is too high for the sample rate.
+pub(super) fn test_period(frequency: f32, timing: &Timing) -> Option<usize> {
+    let period = (timing.sample_rate() / frequency + 0.5) as usize;
+    (period >= MIN_PERIOD).then_some(period)
+}
+
+/// Correlates the recorded samples with the test signal.
+pub(super) struct Correlator {
+    test: ImpedanceTest,
+    /// Sum of the samples multiplied with the sine of the test signal.
+    sin: f32,
+    /// Sum of the samples multiplied with the cosine of the test signal.
+    cos: f32,
+    /// Number of samples so far.
+    count: usize,
+}
+
+impl Correlator {
+    /// Create an empty correlator for the test.
+    pub fn new(test: ImpedanceTest) -> Self {
+        Self {
+            test,
+            sin: 0.0,
+            cos: 0.0,
+            count: 0,
+        }
+    }
+    /// Add the next sample. The first sample must belong to the first frame of a command buffer.
+    pub fn add(&mut self, sample: u16) {
+        let x = sample as f32 - AMPLIFIER_ZERO as f32;
+        let period = self.test.period;
+        let phase = 2.0 * PI * (self.count % period) as f32 / period as f32;
+        self.sin += x * sinf(phase);
+        self.cos += x * cosf(phase);
+        self.count += 1;
+    }
+    /// Number of samples added so far.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+    /// Calculate the impedance from the samples.
+    ///
+    /// The sample of a frame is converted before the DAC is updated in the same frame,
+    /// and the DAC holds its value for a whole frame, so the recorded signal lags the
+    /// test signal by `1 / stride + 1 / 2` frames. This delay is removed from the phase.
+    /// The current leads the DAC voltage by 90°.
+    pub fn impedance(&self, frequency: f32, stride: usize) -> Impedance {
+        let period = self.test.period as f32;
+        let n = self.count.max(1) as f32;
+        let amplitude = 2.0 * sqrtf(self.sin * self.sin + self.cos * self.cos) / n * AMPLIFIER_LSB;
+        // The staircase from the DAC has a slightly smaller fundamental than the sine.
+        let x = PI / period;
+        let current = 2.0
+            * PI
+            * frequency
+            * self.test.scale.capacitance()
+            * DAC_AMPLITUDE
+            * DAC_STEP
+            * (sinf(x) / x);
+        let delay = 360.0 * (1.0 / stride as f32 + 0.5) / period;
+        let mut phase = atan2f(self.cos, self.sin).to_degrees() + delay - 90.0;
+        if phase > 180.0 {
+            phase -= 360.0;
+        } else if phase < -180.0 {
+            phase += 360.0;
+        }
+        Impedance {
+            magnitude: amplitude / current,
+            phase,
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Operations injected into the running command stream.
+//!
+//! The commands of the next buffer are modified in the interrupt right after the buffer swap
+//! has been requested. The changes are placed in the middle of the buffer, so the DMA is far away
+//! from them while they are written. They are undone again in the following interrupt.
+
+use super::{dummy_command, start_calibration, SpiBuffers, Timing, TIMER_FREQUENCY};
+
+/// Number of commands the calibration takes including the calibrate command.
+/// The conversions in between are replaced with dummy commands.
+const CALIBRATION_COMMANDS: usize = 10;
+
+/// An operation on the running ADC.
+#[derive(Clone, Copy, Debug, PartialEq, defmt::Format)]
+pub enum Operation {
+    /// Recalibration of the ADC.
+    Recalibrate,
+    /// Fast settle of the amplifiers.
+    FastSettle,
+}
+
+/// Frames of a packet affected by an operation.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct Marker {
+    /// The operation affecting the frames.
+    pub operation: Operation,
+    /// Index of the first affected frame in the packet.
+    pub first_frame: u16,
+    /// Number of affected frames.
+    pub frame_count: u16,
+}
+
+impl Timing {
+    /// Number of buffers covering at least `duration_ms` milliseconds.
+    fn buffers_for(&self, duration_ms: u32) -> usize {
+        let frames =
+            duration_ms as u64 * TIMER_FREQUENCY as u64 / (1000 * self.sample_period() as u64);
+        let frames_per_buffer = self.frames_per_buffer as u64;
+        ((frames + frames_per_buffer - 1) / frames_per_buffer).max(1) as usize
+    }
+}
+
+impl SpiBuffers {
+    /// Undo the changes to the command buffer and inject the next pending operation.
+    /// Call this in the interrupt after the data of the previous buffer has been sent.
+    /// Only one operation is injected per buffer, the others stay pending.
+    pub(super) fn inject_operations(&mut self) {
+        let timing = self.timing;
+        let buffer_size = timing.buffer_size();
+        if self.injected.take().is_some() {
+            Self::fill_readout_commands(
+                &mut self.tx[0..buffer_size],
+                &self.schedule,
+                &timing,
+                self.mode,
+                &self.registers,
+            );
+        }
+        let frames = timing.frames_per_buffer as u16;
+        let middle = timing.frames_per_buffer / 2;
+        let slot = middle * timing.stride;
+        // The last slot of a frame never holds a conversion.
+        let last = slot + timing.stride - 1;
+        self.marker = if self.settle_buffers > 0 {
+            self.settle_buffers -= 1;
+            if self.settle_buffers == 0 {
+                self.tx[last] = self.registers.write_command(0);
+                self.injected = Some(last..last + 1);
+                Some(Marker {
+                    operation: Operation::FastSettle,
+                    first_frame: 0,
+                    frame_count: middle as u16 + 1,
+                })
+            } else {
+                Some(Marker {
+                    operation: Operation::FastSettle,
+                    first_frame: 0,
+                    frame_count: frames,
+                })
+            }
+        } else if let Some(duration_ms) = self.fast_settle.take() {
+            self.tx[last] = self.registers.fast_settle(true).write_command(0);
+            self.injected = Some(last..last + 1);
+            self.settle_buffers = timing.buffers_for(duration_ms);
+            Some(Marker {
+                operation: Operation::FastSettle,
+                first_frame: middle as u16,
+                frame_count: frames - middle as u16,
+            })
+        } else if core::mem::take(&mut self.recalibrate) {
+            let end = slot + CALIBRATION_COMMANDS;
+            self.tx[slot] = start_calibration();
+            self.tx[slot + 1..end].fill(dummy_command());
+            self.injected = Some(slot..end);
+            Some(Marker {
+                operation: Operation::Recalibrate,
+                first_frame: middle as u16,
+                frame_count: ((end - 1) / timing.stride - middle + 1) as u16,
+            })
+        } else {
+            None
+        };
+    }
+}
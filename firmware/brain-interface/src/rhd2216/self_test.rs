@@ -0,0 +1,103 @@
+//! Chip identification and verification of the startup configuration.
+//!
+//! The startup sequence reads back every register it has written and the ROM registers
+//! identifying the chip. The responses are checked once the startup buffer is complete.
+//!
+//! While recording, the responses to all commands other than conversions are checked
+//! in every buffer to detect a chip that stopped responding.
+
+use core::ops::Range;
+
+use rhd2000_protocol::{RegisterMap, COMPANY_ID, READBACK_START, REGISTER_COUNT, ROM_START};
+
+use super::{swap::Received, Error};
+use crate::rhd2000::Variant;
+
+/// Information read from the ROM registers of the chip.
+#[derive(Clone, Copy, Debug, defmt::Format)]
+pub struct ChipInfo {
+    /// The chip variant detected from the chip ID.
+    pub variant: Variant,
+    /// Die revision from register 60.
+    pub die_revision: u8,
+    /// Whether the chip has bipolar amplifiers, from register 61.
+    pub bipolar: bool,
+    /// Number of amplifiers from register 62.
+    pub amplifier_count: u8,
+    /// Chip ID from register 63.
+    pub chip_id: u8,
+}
+
+/// The response the chip gives to `command`, if it is known in advance.
+/// `command` is in the byte order of the command buffer.
+fn expected_response(command: u16, registers: &RegisterMap) -> Option<u16> {
+    let command = command.to_be();
+    let register = ((command >> 8) & 63) as usize;
+    match command >> 14 {
+        // A write echoes the written value.
+        0b10 => Some(0xff00 | (command & 255)),
+        0b11 => match register {
+            r if r < REGISTER_COUNT => Some(registers.register(r) as u16),
+            r if (40..40 + COMPANY_ID.len()).contains(&r) => Some(COMPANY_ID[r - 40] as u16),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Count the responses to the commands in `slots` that differ from the expected value.
+/// `tx` holds the commands of the buffer and `rx` the received words.
+/// The responses must lie within the buffer.
+pub(super) fn count_bad_responses(
+    tx: &[u16],
+    slots: Range<usize>,
+    rx: &Received,
+    registers: &RegisterMap,
+) -> u16 {
+    let mut bad = 0;
+    for i in slots {
+        if expected_response(tx[i], registers).is_some_and(|v| v != rx.response(i)) {
+            bad += 1;
+        }
+    }
+    bad
+}
+
+/// Check the responses to the startup sequence.
+/// `rx` holds the received startup buffer and `registers` the values that were written.
+pub(super) fn check_startup(rx: &[u16], registers: &RegisterMap) -> Result<ChipInfo, Error> {
+    // Responses arrive two commands after the command.
+    let response = |i: usize| rx[i + 2].to_be();
+    let rom = |n: usize| response(ROM_START + n) as u8;
+    if (0..COMPANY_ID.len()).any(|n| rom(n) != COMPANY_ID[n]) {
+        return Err(Error::ChipNotFound);
+    }
+    let chip_id = rom(8);
+    let info = ChipInfo {
+        variant: Variant::from_chip_id(chip_id).ok_or(Error::UnknownChip(chip_id))?,
+        die_revision: rom(5),
+        bipolar: rom(6) != 0,
+        amplifier_count: rom(7),
+        chip_id,
+    };
+    // A misread chip ID shows in the other ROM registers.
+    if info.bipolar != info.variant.bipolar()
+        || info.amplifier_count as usize != info.variant.amplifier_count()
+    {
+        return Err(Error::UnknownChip(chip_id));
+    }
+    if !info.variant.supported() {
+        return Err(Error::UnsupportedChip(info.variant));
+    }
+    for (r, written) in registers.encode().into_iter().enumerate() {
+        let read = response(READBACK_START + r);
+        if read != written as u16 {
+            return Err(Error::RegisterMismatch {
+                register: r as u8,
+                written,
+                read,
+            });
+        }
+    }
+    Ok(info)
+}
@@ -0,0 +1,109 @@
+//! Timing statistics of the command stream.
+//!
+//! The buffers must be swapped before the overflow space behind the buffer that has just been
+//! completed runs out, so the interrupt at the end of a buffer may only be delayed by the
+//! overflow margin. The delay is measured with the transaction counter, the same way as the
+//! timestamps of the packets. The time the buffers are locked in a critical section, which
+//! delays the interrupt as well, is measured with the cycle counter of the CPU.
+
+use cortex_m::peripheral::DWT;
+
+use super::{SpiBuffers, Timing, SPI_BUFFERS, TIMER_FREQUENCY, TOTAL_BUFFER};
+
+/// Frequency of the CPU cycle counter.
+const CPU_FREQUENCY: u32 = 64_000_000;
+
+/// Timing statistics of the command stream since they were last read.
+#[derive(Clone, Copy, Debug, Default, defmt::Format)]
+pub struct Statistics {
+    /// Number of interrupts at the end of a buffer.
+    pub interrupts: u32,
+    /// Mean delay from the end of a buffer to its interrupt in µs.
+    pub mean_latency: f32,
+    /// Longest delay from the end of a buffer to its interrupt in µs.
+    pub max_latency: f32,
+    /// Largest number of words received into the overflow space before a buffer swap.
+    pub max_overflow_words: u16,
+    /// Number of words that fit into the overflow space.
+    pub overflow_capacity: u16,
+    /// Time in µs until the overflow space runs out, the longest latency the swap can tolerate.
+    pub overflow_margin: f32,
+    /// Longest time the buffers were locked in a critical section in µs.
+    pub max_critical_section: f32,
+}
+
+/// Raw counters updated by the interrupt.
+pub(super) struct Counters {
+    /// Number of interrupts at the end of a buffer.
+    interrupts: u32,
+    /// Sum of the interrupt latencies in 16MHz ticks.
+    latency_sum: u64,
+    /// Longest interrupt latency in 16MHz ticks.
+    max_latency: u32,
+    /// Largest number of words received into the overflow space.
+    max_spill: usize,
+    /// Longest critical section in CPU cycles.
+    max_lock: u32,
+}
+
+impl Counters {
+    /// Counters without any measurements.
+    pub(super) const fn new() -> Self {
+        Self {
+            interrupts: 0,
+            latency_sum: 0,
+            max_latency: 0,
+            max_spill: 0,
+            max_lock: 0,
+        }
+    }
+    /// Record an interrupt that ran `latency` ticks after the end of its buffer.
+    pub(super) fn interrupt(&mut self, latency: u32) {
+        self.interrupts = self.interrupts.saturating_add(1);
+        self.latency_sum += latency as u64;
+        self.max_latency = self.max_latency.max(latency);
+    }
+    /// Record the number of words received into the overflow space before a swap.
+    pub(super) fn spill(&mut self, words: usize) {
+        self.max_spill = self.max_spill.max(words);
+    }
+    /// Record a critical section of `cycles` CPU cycles.
+    fn lock(&mut self, cycles: u32) {
+        self.max_lock = self.max_lock.max(cycles);
+    }
+    /// Convert the counters for the command stream with `timing` and reset them.
+    pub(super) fn take(&mut self, timing: &Timing) -> Statistics {
+        let counters = core::mem::replace(self, Self::new());
+        let capacity = TOTAL_BUFFER - timing.buffer_size();
+        let micros = |ticks: f32| ticks * 1e6 / TIMER_FREQUENCY as f32;
+        Statistics {
+            interrupts: counters.interrupts,
+            mean_latency: micros(counters.latency_sum as f32 / counters.interrupts.max(1) as f32),
+            max_latency: micros(counters.max_latency as f32),
+            max_overflow_words: counters.max_spill as u16,
+            overflow_capacity: capacity as u16,
+            overflow_margin: micros((capacity * timing.timer_interval as usize) as f32),
+            max_critical_section: counters.max_lock as f32 * 1e6 / CPU_FREQUENCY as f32,
+        }
+    }
+}
+
+/// Start the cycle counter of the CPU.
+pub(super) fn enable_cycle_counter() {
+    let mut p = unsafe { cortex_m::Peripherals::steal() };
+    p.DCB.enable_trace();
+    p.DWT.enable_cycle_counter();
+}
+
+/// Run `f` with the buffers locked and record how long they stayed locked.
+pub(super) fn with_buffers<R>(f: impl FnOnce(&mut SpiBuffers) -> R) -> R {
+    critical_section::with(|cs| {
+        let start = DWT::cycle_count();
+        let mut buffers = SPI_BUFFERS.borrow_ref_mut(cs);
+        let result = f(&mut buffers);
+        buffers
+            .counters
+            .lock(DWT::cycle_count().wrapping_sub(start));
+        result
+    })
+}
@@ -0,0 +1,191 @@
+//! Swapping the DMA buffers while the command stream keeps running.
+//!
+//! The SPI runs in array list mode, so the DMA pointers advance by one slot with every
+//! transaction and have to be moved back at the end of each buffer. A transaction that
+//! starts while the pointers are written may use the old or the new values, so they are only
+//! written in the safe window between the end of one transaction and the start of the next.
+//!
+//! The pointers are set to absolute addresses derived from TIMER2, which counts the
+//! transactions since the end of the buffer. The slots transferred before the swap stay in
+//! the overflow space behind the previous buffer and are read from there, nothing is copied.
+//! If the interrupt missed the safe window, the swap is retried from the TIMER1 interrupt,
+//! which fires right after the next transaction. A write that raced with a transaction is
+//! simply repeated, because its result only depends on the counter. If the swap is still
+//! pending at the end of the next buffer, that buffer lies in the overflow space and is dropped,
+//! and the TIMER1 interrupt moves the pointers to the start of the following buffer.
+//!
+//! Before the pointers are written they are checked against the counter. Any difference means
+//! that a command slot has been skipped or repeated and is counted in [`Status::slot_errors`].
+//!
+//! [`Status::slot_errors`]: super::Status::slot_errors
+
+use defmt::warn;
+use embassy_nrf::pac;
+use embassy_time::Instant;
+
+use super::{
+    spi_registers, timer1_disable_cc1_isr, timer1_enable_cc1_isr, timer1_registers,
+    timer2_registers, SpiBuffers, State, TIMER_FREQUENCY,
+};
+
+/// Number of 16MHz ticks after the start of a transaction at which the safe window opens.
+/// A transaction of 16 bits at 16MHz is finished by then.
+pub(super) const SAFE_START: u32 = 25;
+/// Number of 16MHz ticks before the start of the next transaction at which the safe window closes.
+const SAFE_MARGIN: u32 = 20;
+/// Capture channel used on both timers.
+const CAPTURE: usize = 3;
+
+/// State of the buffer swap.
+pub(super) struct Swap {
+    /// The DMA pointers still have to be moved to the buffer for the current state.
+    pending: bool,
+    /// An attempt raced with a transaction or the swap was missed for a whole buffer,
+    /// so the pointers cannot be checked anymore.
+    raced: bool,
+    /// Number of times the swap was retried from the TIMER1 interrupt.
+    pub(super) retries: u16,
+    /// Number of slots of the current buffer that were transferred before the swap.
+    pub(super) spill: usize,
+}
+
+impl Swap {
+    /// No swap in progress.
+    pub(super) const fn new() -> Self {
+        Self {
+            pending: false,
+            raced: false,
+            retries: 0,
+            spill: 0,
+        }
+    }
+}
+
+/// The responses of one buffer.
+/// The first slots may still lie in the overflow space behind the previous buffer.
+pub(super) struct Received<'a> {
+    /// Slots transferred before the swap.
+    pub(super) spill: &'a [u16],
+    /// The buffer the DMA pointers were moved to.
+    pub(super) buffer: &'a [u16],
+}
+
+impl<'a> Received<'a> {
+    /// Response to the command in `slot`. It arrives two slots later.
+    pub(super) fn response(&self, slot: usize) -> u16 {
+        let i = slot + 2;
+        match self.spill.get(i) {
+            Some(&v) => v.to_be(),
+            None => self.buffer[i].to_be(),
+        }
+    }
+}
+
+/// Capture the value of a timer.
+fn capture(r: &pac::timer2::RegisterBlock) -> u32 {
+    r.tasks_capture[CAPTURE].write(|w| unsafe { w.bits(1) });
+    r.cc[CAPTURE].read().bits()
+}
+
+impl SpiBuffers {
+    /// Switch to state `next` and move the DMA pointers to its buffer.
+    /// Call this in the interrupt at the end of every buffer.
+    /// If the safe window has already passed, the swap is finished by [`SpiBuffers::retry_swap`].
+    /// Returns `false` if the previous swap is still pending. The buffer that has just been
+    /// completed was then received into the overflow space and has to be dropped.
+    pub(super) unsafe fn request_swap(&mut self, next: State) -> bool {
+        if self.swap.pending {
+            // The previous swap did not happen for a whole buffer. It stays pending and the
+            // pointers are moved relative to the end of this buffer, so the state is kept.
+            warn!("Buffer swap missed");
+            self.slot_errors = self.slot_errors.saturating_add(1);
+            self.swap.raced = true;
+            return false;
+        }
+        self.state = next;
+        self.swap.pending = true;
+        self.swap.raced = false;
+        self.swap.retries = 0;
+        if !self.try_swap() {
+            timer1_enable_cc1_isr();
+        }
+        true
+    }
+    /// Retry a pending swap. Call this every time the TIMER1 interrupt runs.
+    pub(super) unsafe fn retry_swap(&mut self) {
+        if self.swap.pending {
+            self.swap.retries = self.swap.retries.saturating_add(1);
+            if !self.try_swap() {
+                return;
+            }
+        }
+        timer1_disable_cc1_isr();
+    }
+    /// Number of 16MHz ticks since the last command of the buffer that has just been completed
+    /// was sent. Call this at the start of the interrupt, before the swap.
+    ///
+    /// The commands sent since the end of the buffer are counted by TIMER2
+    /// and TIMER1 holds the time since the last one.
+    pub(super) fn interrupt_latency(&self) -> u32 {
+        let since_end = capture(timer2_registers()) * self.timing.timer_interval;
+        since_end + capture(timer1_registers())
+    }
+    /// Time in µs since boot at which the first command of the buffer that has just been
+    /// completed was sent. `latency` is the result of [`SpiBuffers::interrupt_latency`].
+    ///
+    /// The RTC is read when the interrupt runs, so the latency has to be taken into account.
+    pub(super) fn buffer_start_time(&self, latency: u32) -> u64 {
+        let now = Instant::now().as_micros();
+        let interval = self.timing.timer_interval as u64;
+        let buffer = (self.timing.buffer_size() as u64 - 1) * interval;
+        let ticks = latency as u64 + buffer;
+        now.saturating_sub(ticks * 1_000_000 / TIMER_FREQUENCY as u64)
+    }
+    /// Responses of the buffer `filled` that has just been completed.
+    pub(super) fn received(&self, filled: &State, spill: usize) -> Received<'_> {
+        let buffer_size = self.timing.buffer_size();
+        let (buffer, previous) = match filled {
+            State::Rx2 => (&self.rx2, &self.rx1),
+            _ => (&self.rx1, &self.rx2),
+        };
+        Received {
+            spill: &previous[buffer_size..buffer_size + spill],
+            buffer,
+        }
+    }
+    /// Write the DMA pointers if TIMER1 is in the safe window.
+    /// Returns `false` if the swap has to be tried again.
+    unsafe fn try_swap(&mut self) -> bool {
+        let spi = spi_registers();
+        let upper = self.timing.timer_interval - SAFE_MARGIN;
+        let before = capture(timer1_registers());
+        if !(SAFE_START..=upper).contains(&before) {
+            return false;
+        }
+        let n = capture(timer2_registers()) as usize;
+        let (target, filled) = match self.state {
+            State::Rx2 => (self.rx2_address(), self.rx1_address()),
+            _ => (self.rx1_address(), self.rx2_address()),
+        };
+        let slot = |base: u32, i: usize| base + 2 * i as u32;
+        let tx = spi.txd.ptr.read().bits();
+        let rx = spi.rxd.ptr.read().bits();
+        spi.txd.ptr.write(|w| w.bits(slot(self.tx_address(), n)));
+        spi.rxd.ptr.write(|w| w.bits(slot(target, n)));
+        let after = capture(timer1_registers());
+        if after < before || after > upper {
+            // A transaction may have started in between.
+            self.swap.raced = true;
+            return false;
+        }
+        // Since the last swap the pointers have advanced by one slot per transaction.
+        let end = self.timing.buffer_size() + n;
+        if !self.swap.raced && (tx != slot(self.tx_address(), end) || rx != slot(filled, end)) {
+            warn!("DMA pointers out of step");
+            self.slot_errors = self.slot_errors.saturating_add(1);
+        }
+        self.swap.spill = n;
+        self.swap.pending = false;
+        true
+    }
+}
@@ -0,0 +1,31 @@
+//! Synthetic signals replacing the samples of the chip.
+//!
+//! The command stream keeps running, so the timing, the status and the auxiliary measurements
+//! stay real and the packets take the same path as during a recording. Only the samples are
+//! replaced, after they have left the interrupt, so the time spent there does not change.
+//!
+//! The sample number passed to the [`Waveform`] is the index of the frame. A channel with a
+//! rate divisor therefore advances by the divisor from one of its samples to the next.
+
+use rhd2000_protocol::{Schedule, Waveform};
+
+use super::{Data, SampleFormat};
+
+/// Replace the samples of `data` with `waveform`.
+pub(super) fn replace_samples(data: &mut Data, schedule: &Schedule, waveform: &Waveform) {
+    let twos_comp = data.format == SampleFormat::TwosComplement;
+    let first_frame = data.first_frame;
+    let mut samples = data.frames.iter_mut();
+    for f in 0.. {
+        for lane in 0..schedule.lanes() {
+            let Some(channel) = schedule.channel(f, lane) else {
+                continue;
+            };
+            let Some(sample) = samples.next() else {
+                return;
+            };
+            let value = waveform.sample(channel, (first_frame + f as u64) as u32);
+            *sample = if twos_comp { value ^ 0x8000 } else { value };
+        }
+    }
+}
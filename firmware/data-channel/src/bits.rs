@@ -0,0 +1,69 @@
+//! Bit streams of the compressed and packed samples, least significant bit first.
+
+use crate::DecodeError;
+
+/// Writes bits into a byte buffer.
+pub(super) struct BitWriter<'a> {
+    out: &'a mut [u8],
+    pos: usize,
+    acc: u32,
+    bits: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    pub(super) fn new(out: &'a mut [u8]) -> Self {
+        Self {
+            out,
+            pos: 0,
+            acc: 0,
+            bits: 0,
+        }
+    }
+    /// Append the lowest `n` bits of `value`, at most 16. Returns `None` if the buffer is full.
+    pub(super) fn write(&mut self, value: u32, n: u32) -> Option<()> {
+        self.acc |= value << self.bits;
+        self.bits += n;
+        while self.bits >= 8 {
+            *self.out.get_mut(self.pos)? = self.acc as u8;
+            self.pos += 1;
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+        Some(())
+    }
+    /// Write the last incomplete byte. Returns the number of bytes written.
+    pub(super) fn finish(self) -> Option<usize> {
+        if self.bits == 0 {
+            return Some(self.pos);
+        }
+        *self.out.get_mut(self.pos)? = self.acc as u8;
+        Some(self.pos + 1)
+    }
+}
+
+/// Reads bits from a byte buffer.
+pub(super) struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+    /// Read a single bit.
+    pub(super) fn bit(&mut self) -> Result<u32, DecodeError> {
+        let byte = self.data.get(self.pos / 8).ok_or(DecodeError::TooShort)?;
+        let bit = (byte >> (self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(bit as u32)
+    }
+    /// Read `n` bits.
+    pub(super) fn read(&mut self, n: u32) -> Result<u32, DecodeError> {
+        let mut value = 0;
+        for i in 0..n {
+            value |= self.bit()? << i;
+        }
+        Ok(value)
+    }
+}
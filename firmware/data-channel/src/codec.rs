@@ -0,0 +1,526 @@
+//! Encoding of the packets sent by the brain interface.
+//!
+//! Every packet starts with a [`Header`] of [`HEADER_SIZE`] bytes that identifies the format
+//! and describes the recording, followed by the payload of its [`PacketType`].
+//! Packets with samples continue with a [`SampleInfo`] and the samples,
+//! either packed or compressed as given by the [`SampleEncoding`] and the bit depth of the header.
+//! Envelope packets continue with an [`EnvelopeInfo`] and the points of the envelope.
+//! All numbers are little endian.
+//!
+//! The encoders work without allocation, so the firmware can use them in place.
+//! The decoders borrow from the packet; with the `std` feature [`decode_samples`]
+//! also collects the samples.
+
+use core::fmt;
+
+use crate::BitDepth;
+
+/// First bytes of every packet.
+pub const MAGIC: [u8; 2] = *b"BI";
+/// Version of the packet format. Incremented with every incompatible change.
+pub const VERSION: u8 = 1;
+/// Size of the [`Header`] in bytes.
+pub const HEADER_SIZE: usize = 28;
+/// Size of the [`SampleInfo`] in bytes.
+pub const SAMPLE_INFO_SIZE: usize = 16;
+/// Size of the [`EnvelopeInfo`] in bytes.
+pub const ENVELOPE_INFO_SIZE: usize = 16;
+
+/// Kind of the payload of a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PacketType {
+    /// Samples of the amplifier channels.
+    Samples = 0,
+    /// Electrode impedances.
+    Impedance = 1,
+    /// Auxiliary measurements of a packet with samples.
+    Aux = 2,
+    /// Chip identification, register values, scale factors and schedule of a recording.
+    Config = 3,
+    /// Timing statistics of the command stream.
+    Statistics = 4,
+    /// Answer to a command of the host.
+    Response = 5,
+    /// Smallest and largest sample of every channel, sent instead of the samples.
+    Envelope = 6,
+    /// Status of the device, sent without a command when the recording stops by itself.
+    Status = 7,
+}
+
+impl TryFrom<u8> for PacketType {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Samples,
+            1 => Self::Impedance,
+            2 => Self::Aux,
+            3 => Self::Config,
+            4 => Self::Statistics,
+            5 => Self::Response,
+            6 => Self::Envelope,
+            7 => Self::Status,
+            v => return Err(DecodeError::UnknownType(v)),
+        })
+    }
+}
+
+/// Encoding of the samples.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SampleFormat {
+    /// Unsigned with 32768 for 0V.
+    OffsetBinary = 0,
+    /// Two's complement with 0 for 0V.
+    TwosComplement = 1,
+}
+
+impl TryFrom<u8> for SampleFormat {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::OffsetBinary,
+            1 => Self::TwosComplement,
+            v => return Err(DecodeError::UnknownFormat(v)),
+        })
+    }
+}
+
+/// How the samples of a packet are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SampleEncoding {
+    /// Packed with the bit depth of the header, read with [`unpack`](crate::unpack).
+    /// At 16 bits these are little endian words, read with [`samples`].
+    Plain = 0,
+    /// Compressed with [`compress`](crate::compress), read with [`decompress`](crate::decompress).
+    Rice = 1,
+}
+
+impl TryFrom<u8> for SampleEncoding {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Plain,
+            1 => Self::Rice,
+            v => return Err(DecodeError::UnknownEncoding(v)),
+        })
+    }
+}
+
+/// Reasons why a packet cannot be decoded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DecodeError {
+    /// The packet ends before the end of its fixed fields.
+    TooShort,
+    /// The packet does not start with [`MAGIC`].
+    BadMagic,
+    /// The packet has a different [`VERSION`]. Contains the version of the packet.
+    UnsupportedVersion(u8),
+    /// The packet type is unknown. Contains the packet type.
+    UnknownType(u8),
+    /// The sample format is unknown. Contains the sample format.
+    UnknownFormat(u8),
+    /// The sample encoding is unknown. Contains the sample encoding.
+    UnknownEncoding(u8),
+    /// The bit depth is not one of [`BIT_DEPTHS`](crate::BIT_DEPTHS). Contains the bit depth.
+    UnsupportedBitDepth(u8),
+    /// The result code of a response is unknown. Contains the result code.
+    UnknownResult(u8),
+    /// The impedance scale is unknown. Contains the scale.
+    UnknownScale(u8),
+    /// A parameter of a command is outside of its valid range.
+    InvalidParameter,
+    /// The payload does not belong to the expected packet type.
+    WrongType(PacketType),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooShort => write!(f, "packet too short"),
+            Self::BadMagic => write!(f, "not a brain interface packet"),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported packet version {v}"),
+            Self::UnknownType(t) => write!(f, "unknown packet type {t}"),
+            Self::UnknownFormat(s) => write!(f, "unknown sample format {s}"),
+            Self::UnknownEncoding(e) => write!(f, "unknown sample encoding {e}"),
+            Self::UnsupportedBitDepth(b) => write!(f, "unsupported bit depth {b}"),
+            Self::UnknownResult(r) => write!(f, "unknown result code {r}"),
+            Self::UnknownScale(s) => write!(f, "unknown impedance scale {s}"),
+            Self::InvalidParameter => write!(f, "command parameter out of range"),
+            Self::WrongType(t) => write!(f, "unexpected packet type {t:?}"),
+        }
+    }
+}
+
+#[cfg(any(feature = "std", test))]
+impl std::error::Error for DecodeError {}
+
+/// Fields at the start of every packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Header {
+    /// Kind of the payload.
+    pub packet_type: PacketType,
+    /// Number of the buffer the packet belongs to. Missing numbers are lost buffers.
+    /// Samples and auxiliary measurements of the same buffer share the number,
+    /// the other packet types use 0.
+    pub sequence: u32,
+    /// Time in µs since the brain interface was switched on, or since the time set by the host.
+    /// For samples the time the first frame was sampled, otherwise the time the packet was sent.
+    pub timestamp: u64,
+    /// Bit mask of the recorded amplifier channels.
+    pub channel_mask: u64,
+    /// Number of channels in the channel mask.
+    pub channels: u8,
+    /// Encoding of the samples.
+    pub format: SampleFormat,
+    /// Health of the recording, see the data format documentation.
+    pub flags: u8,
+    /// How the samples are stored. [`SampleEncoding::Plain`] for the other packet types.
+    pub encoding: SampleEncoding,
+    /// Number of upper bits kept of every sample. [`BitDepth::FULL`] for the other packet types.
+    pub bit_depth: BitDepth,
+}
+
+impl Header {
+    /// Encode the header.
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut b = [0; HEADER_SIZE];
+        b[0..2].copy_from_slice(&MAGIC);
+        b[2] = VERSION;
+        b[3] = self.packet_type as u8;
+        b[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        b[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        b[16..24].copy_from_slice(&self.channel_mask.to_le_bytes());
+        b[24] = self.channels;
+        b[25] = self.format as u8;
+        b[26] = self.flags;
+        b[27] = self.encoding as u8 | (16 - self.bit_depth.get()) << 4;
+        b
+    }
+    /// Decode the header of `packet`. Returns the header and the payload behind it.
+    pub fn decode(packet: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        if packet.len() < HEADER_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        if packet[0..2] != MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        if packet[2] != VERSION {
+            return Err(DecodeError::UnsupportedVersion(packet[2]));
+        }
+        let header = Self {
+            packet_type: packet[3].try_into()?,
+            sequence: u32::from_le_bytes(array(&packet[4..8])),
+            timestamp: u64::from_le_bytes(array(&packet[8..16])),
+            channel_mask: u64::from_le_bytes(array(&packet[16..24])),
+            channels: packet[24],
+            format: packet[25].try_into()?,
+            flags: packet[26],
+            encoding: (packet[27] & 0xf).try_into()?,
+            bit_depth: (16 - (packet[27] >> 4)).try_into()?,
+        };
+        Ok((header, &packet[HEADER_SIZE..]))
+    }
+}
+
+/// Fields between the header and the samples of a packet with samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SampleInfo {
+    /// Number of 16MHz ticks between two frames.
+    pub sample_period: u32,
+    /// Index of the first frame affected by a recalibration or fast settle.
+    pub first_marked: u16,
+    /// Number of frames affected by a recalibration or fast settle.
+    pub marked_count: u16,
+    /// Index of the first frame of the packet since the start of the command stream.
+    pub first_frame: u64,
+}
+
+impl SampleInfo {
+    /// Encode the fields.
+    pub fn encode(&self) -> [u8; SAMPLE_INFO_SIZE] {
+        let mut b = [0; SAMPLE_INFO_SIZE];
+        b[0..4].copy_from_slice(&self.sample_period.to_le_bytes());
+        b[4..6].copy_from_slice(&self.first_marked.to_le_bytes());
+        b[6..8].copy_from_slice(&self.marked_count.to_le_bytes());
+        b[8..16].copy_from_slice(&self.first_frame.to_le_bytes());
+        b
+    }
+    /// Decode the fields from the payload of a packet with samples.
+    /// Returns the fields and the encoded samples behind them.
+    pub fn decode(payload: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        if payload.len() < SAMPLE_INFO_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        let info = Self {
+            sample_period: u32::from_le_bytes(array(&payload[0..4])),
+            first_marked: u16::from_le_bytes(array(&payload[4..6])),
+            marked_count: u16::from_le_bytes(array(&payload[6..8])),
+            first_frame: u64::from_le_bytes(array(&payload[8..16])),
+        };
+        Ok((info, &payload[SAMPLE_INFO_SIZE..]))
+    }
+}
+
+/// Fields between the header and the points of an envelope packet.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EnvelopeInfo {
+    /// Number of 16MHz ticks between two frames.
+    pub sample_period: u32,
+    /// Number of frames covered by every point.
+    pub frames_per_point: u32,
+    /// Index of the first frame of the first point since the start of the command stream.
+    pub first_frame: u64,
+}
+
+impl EnvelopeInfo {
+    /// Encode the fields.
+    pub fn encode(&self) -> [u8; ENVELOPE_INFO_SIZE] {
+        let mut b = [0; ENVELOPE_INFO_SIZE];
+        b[0..4].copy_from_slice(&self.sample_period.to_le_bytes());
+        b[4..8].copy_from_slice(&self.frames_per_point.to_le_bytes());
+        b[8..16].copy_from_slice(&self.first_frame.to_le_bytes());
+        b
+    }
+    /// Decode the fields from the payload of an envelope packet.
+    /// Returns the fields and the encoded points behind them.
+    pub fn decode(payload: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        if payload.len() < ENVELOPE_INFO_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        let info = Self {
+            sample_period: u32::from_le_bytes(array(&payload[0..4])),
+            frames_per_point: u32::from_le_bytes(array(&payload[4..8])),
+            first_frame: u64::from_le_bytes(array(&payload[8..16])),
+        };
+        Ok((info, &payload[ENVELOPE_INFO_SIZE..]))
+    }
+}
+
+/// Smallest and largest sample of a channel in one point of an envelope.
+pub type MinMax = (u16, u16);
+
+/// The points encoded behind the [`EnvelopeInfo`], channel by channel for every point.
+/// A trailing incomplete pair is ignored.
+pub fn envelope(encoded: &[u8]) -> impl Iterator<Item = MinMax> + '_ {
+    encoded.chunks_exact(4).map(|b| {
+        (
+            u16::from_le_bytes([b[0], b[1]]),
+            u16::from_le_bytes([b[2], b[3]]),
+        )
+    })
+}
+
+/// Decode an envelope packet.
+#[cfg(any(feature = "std", test))]
+pub fn decode_envelope(
+    packet: &[u8],
+) -> Result<(Header, EnvelopeInfo, std::vec::Vec<MinMax>), DecodeError> {
+    let (header, payload) = Header::decode(packet)?;
+    if header.packet_type != PacketType::Envelope {
+        return Err(DecodeError::WrongType(header.packet_type));
+    }
+    let (info, encoded) = EnvelopeInfo::decode(payload)?;
+    Ok((header, info, envelope(encoded).collect()))
+}
+
+/// The samples encoded behind the [`SampleInfo`], in their raw 16 bit form.
+/// A trailing odd byte is ignored.
+pub fn samples(encoded: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    encoded
+        .chunks_exact(2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+}
+
+/// Decode a packet with samples in either encoding, shifted back to 16 bits.
+#[cfg(any(feature = "std", test))]
+pub fn decode_samples(
+    packet: &[u8],
+) -> Result<(Header, SampleInfo, std::vec::Vec<u16>), DecodeError> {
+    let (header, payload) = Header::decode(packet)?;
+    if header.packet_type != PacketType::Samples {
+        return Err(DecodeError::WrongType(header.packet_type));
+    }
+    let (info, encoded) = SampleInfo::decode(payload)?;
+    let samples = match header.encoding {
+        SampleEncoding::Plain => crate::unpack(encoded, header.bit_depth.get()).collect(),
+        SampleEncoding::Rice => {
+            let mut samples = std::vec![0; crate::compressed_len(encoded)?];
+            crate::decompress(encoded, &mut samples)?;
+            for s in &mut samples {
+                *s <<= 16 - header.bit_depth.get();
+            }
+            samples
+        }
+    };
+    Ok((header, info, samples))
+}
+
+/// Copy a slice of the right length into an array.
+fn array<const N: usize>(b: &[u8]) -> [u8; N] {
+    let mut a = [0; N];
+    a.copy_from_slice(b);
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: Header = Header {
+        packet_type: PacketType::Samples,
+        sequence: 0x1234_5678,
+        timestamp: 0x0102_0304_0506_0708,
+        channel_mask: 0xff00_0000_0000_00f0,
+        channels: 12,
+        format: SampleFormat::TwosComplement,
+        flags: 0b10011,
+        encoding: SampleEncoding::Plain,
+        bit_depth: BitDepth::FULL,
+    };
+
+    fn encode_samples(header: &Header, info: &SampleInfo, samples: &[u16]) -> Vec<u8> {
+        let mut packet = header.encode().to_vec();
+        packet.extend_from_slice(&info.encode());
+        for s in samples {
+            packet.extend_from_slice(&s.to_le_bytes());
+        }
+        packet
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut packet = HEADER.encode().to_vec();
+        packet.extend_from_slice(&[7, 8, 9]);
+        assert_eq!(&packet[..4], b"BI\x01\x00");
+        let (header, payload) = Header::decode(&packet).unwrap();
+        assert_eq!(header, HEADER);
+        assert_eq!(payload, [7, 8, 9]);
+        for packet_type in [
+            PacketType::Config,
+            PacketType::Statistics,
+            PacketType::Status,
+        ] {
+            let header = Header {
+                packet_type,
+                ..HEADER
+            };
+            assert_eq!(Header::decode(&header.encode()).unwrap().0, header);
+        }
+        for bit_depth in crate::BIT_DEPTHS {
+            let header = Header {
+                encoding: SampleEncoding::Rice,
+                bit_depth: bit_depth.try_into().unwrap(),
+                ..HEADER
+            };
+            let packet = header.encode();
+            assert_eq!(packet[27], 1 | (16 - bit_depth) << 4);
+            assert_eq!(Header::decode(&packet).unwrap().0, header);
+        }
+    }
+
+    #[test]
+    fn samples_round_trip() {
+        let info = SampleInfo {
+            sample_period: 800,
+            first_marked: 3,
+            marked_count: 40,
+            first_frame: 1 << 40,
+        };
+        let samples: Vec<u16> = (0..100).map(|i| i * 655).collect();
+        let packet = encode_samples(&HEADER, &info, &samples);
+        assert_eq!(packet.len(), HEADER_SIZE + SAMPLE_INFO_SIZE + 200);
+        assert_eq!(
+            decode_samples(&packet).unwrap(),
+            (HEADER, info, samples.clone())
+        );
+        let header = Header {
+            encoding: SampleEncoding::Rice,
+            ..HEADER
+        };
+        let mut packet = header.encode().to_vec();
+        packet.extend_from_slice(&info.encode());
+        let mut compressed = [0; 200];
+        let n = crate::compress(&samples, 12, &mut compressed).unwrap();
+        packet.extend_from_slice(&compressed[..n]);
+        assert_eq!(
+            decode_samples(&packet).unwrap(),
+            (header, info, samples.clone())
+        );
+        let header = Header {
+            bit_depth: BitDepth::try_from(12).unwrap(),
+            ..HEADER
+        };
+        let mut reduced = samples.clone();
+        crate::reduce(&mut reduced, 12, header.format);
+        let mut packet = header.encode().to_vec();
+        packet.extend_from_slice(&info.encode());
+        let mut packed = [0; 150];
+        let n = crate::pack(&reduced, 12, &mut packed);
+        packet.extend_from_slice(&packed[..n]);
+        assert_eq!(packet.len(), HEADER_SIZE + SAMPLE_INFO_SIZE + 150);
+        let truncated = samples.iter().map(|s| s & 0xfff0).collect();
+        assert_eq!(decode_samples(&packet).unwrap(), (header, info, truncated));
+    }
+
+    #[test]
+    fn envelope_round_trip() {
+        let header = Header {
+            packet_type: PacketType::Envelope,
+            ..HEADER
+        };
+        let info = EnvelopeInfo {
+            sample_period: 1600,
+            frames_per_point: 250,
+            first_frame: 1000,
+        };
+        let points: Vec<(u16, u16)> = (0..24).map(|i| (i * 100, i * 100 + 50)).collect();
+        let mut packet = header.encode().to_vec();
+        packet.extend_from_slice(&info.encode());
+        for (min, max) in &points {
+            packet.extend_from_slice(&min.to_le_bytes());
+            packet.extend_from_slice(&max.to_le_bytes());
+        }
+        assert_eq!(packet.len(), HEADER_SIZE + ENVELOPE_INFO_SIZE + 96);
+        assert_eq!(decode_envelope(&packet).unwrap(), (header, info, points));
+        assert_eq!(
+            decode_samples(&packet),
+            Err(DecodeError::WrongType(PacketType::Envelope))
+        );
+    }
+
+    #[test]
+    fn invalid_packets() {
+        let packet = HEADER.encode();
+        let modified = |i: usize, v: u8| {
+            let mut p = packet;
+            p[i] = v;
+            Header::decode(&p).map(|(h, _)| h)
+        };
+        assert_eq!(Header::decode(&packet[..27]), Err(DecodeError::TooShort));
+        assert_eq!(modified(0, b'X'), Err(DecodeError::BadMagic));
+        assert_eq!(modified(2, 2), Err(DecodeError::UnsupportedVersion(2)));
+        assert_eq!(modified(3, 9), Err(DecodeError::UnknownType(9)));
+        assert_eq!(EnvelopeInfo::decode(&[0; 15]), Err(DecodeError::TooShort));
+        assert_eq!(modified(25, 2), Err(DecodeError::UnknownFormat(2)));
+        assert_eq!(modified(27, 3), Err(DecodeError::UnknownEncoding(3)));
+        assert_eq!(
+            modified(27, 0x10),
+            Err(DecodeError::UnsupportedBitDepth(15))
+        );
+        let config = Header {
+            packet_type: PacketType::Config,
+            ..HEADER
+        };
+        assert_eq!(
+            decode_samples(&config.encode()),
+            Err(DecodeError::WrongType(PacketType::Config))
+        );
+        assert_eq!(decode_samples(&packet), Err(DecodeError::TooShort));
+    }
+}
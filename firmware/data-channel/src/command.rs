@@ -0,0 +1,632 @@
+//! Commands from the host to the brain interface and the responses to them.
+//!
+//! A command starts with [`MAGIC`] and [`VERSION`] like the packets of the brain interface,
+//! followed by the [`Opcode`], a tag chosen by the host and the parameters.
+//! Every command is answered with a packet of type [`PacketType::Response`] that repeats the
+//! opcode and the tag, so the host can match the response to its command.
+//!
+//! [`PacketType::Response`]: crate::PacketType::Response
+
+use crate::{BitDepth, DecodeError, SampleEncoding, SampleFormat, MAGIC, VERSION};
+
+/// Size of the fields before the parameters of a command.
+pub const COMMAND_HEADER_SIZE: usize = 5;
+/// Size of an encoded [`RecordingConfig`].
+pub const CONFIG_SIZE: usize = 54;
+/// Size of encoded [`ImpedanceParams`].
+pub const IMPEDANCE_PARAMS_SIZE: usize = 8;
+/// Highest number of periods of the impedance test signal per channel.
+pub const MAX_IMPEDANCE_PERIODS: u16 = 1000;
+/// Size of the encoded synthetic signal in a [`RecordingConfig`].
+pub const SIGNAL_SIZE: usize = 9;
+/// Size of the longest command.
+pub const MAX_COMMAND_SIZE: usize = COMMAND_HEADER_SIZE + CONFIG_SIZE;
+/// Size of a [`Response`] without its data.
+pub const RESPONSE_SIZE: usize = 4;
+/// Number of amplifier channels in a [`RecordingConfig`].
+const CONFIG_CHANNELS: usize = 64;
+
+/// Number identifying a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Opcode {
+    /// Start recording with the current configuration.
+    Start = 1,
+    /// Stop recording. The connection stays open.
+    Stop = 2,
+    /// Replace the configuration. A running recording restarts with it.
+    SetConfig = 3,
+    /// Report whether the brain interface is recording.
+    Status = 4,
+    /// Answer with the same token.
+    Ping = 5,
+    /// Set the time of the timestamps.
+    SetTime = 6,
+    /// Mark an event in the recording.
+    MarkEvent = 7,
+    /// Measure the electrode impedances.
+    MeasureImpedance = 8,
+}
+
+/// Outcome of a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ResultCode {
+    /// The command was carried out.
+    Ok = 0,
+    /// The opcode is unknown.
+    UnknownCommand = 1,
+    /// The command is too short or does not start with [`MAGIC`].
+    Malformed = 2,
+    /// The command has a different [`VERSION`].
+    UnsupportedVersion = 3,
+    /// The recording could not be started with the configuration.
+    StartFailed = 4,
+    /// The impedances could not be measured.
+    MeasurementFailed = 5,
+}
+
+impl TryFrom<u8> for ResultCode {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Ok,
+            1 => Self::UnknownCommand,
+            2 => Self::Malformed,
+            3 => Self::UnsupportedVersion,
+            4 => Self::StartFailed,
+            5 => Self::MeasurementFailed,
+            v => return Err(DecodeError::UnknownResult(v)),
+        })
+    }
+}
+
+/// Configuration of a recording as sent by the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RecordingConfig {
+    /// Bit mask of the amplifier channels to record.
+    pub channel_mask: u64,
+    /// Requested sample rate per channel in Hz.
+    pub sample_rate: u32,
+    /// Requested upper cutoff of the amplifiers in Hz.
+    pub upper_bandwidth: f32,
+    /// Requested lower cutoff of the amplifiers in Hz.
+    pub lower_bandwidth: f32,
+    /// Requested cutoff of the DSP offset removal in Hz, 0 to disable it.
+    pub dsp_cutoff: f32,
+    /// Encoding of the samples.
+    pub format: SampleFormat,
+    /// Rate divisor of each amplifier channel. Must be 1, 2, 4 or 8.
+    pub divisors: [u8; CONFIG_CHANNELS],
+    /// Encoded `Waveform` of the `rhd2000-protocol` crate replacing the samples,
+    /// all zeros for the samples of the chip.
+    pub signal: [u8; SIGNAL_SIZE],
+    /// How the samples are sent. Compressed samples are only sent if that saves space.
+    pub encoding: SampleEncoding,
+    /// Number of upper bits kept of every sample.
+    pub bit_depth: BitDepth,
+    /// Number of points per second of the envelope sent instead of the samples,
+    /// 0 to send the samples.
+    pub envelope_rate: u16,
+}
+
+impl RecordingConfig {
+    /// Encode the configuration. The divisors are stored as 2 bit exponents.
+    pub fn encode(&self) -> [u8; CONFIG_SIZE] {
+        let mut b = [0; CONFIG_SIZE];
+        b[0..8].copy_from_slice(&self.channel_mask.to_le_bytes());
+        b[8..12].copy_from_slice(&self.sample_rate.to_le_bytes());
+        b[12..16].copy_from_slice(&self.upper_bandwidth.to_le_bytes());
+        b[16..20].copy_from_slice(&self.lower_bandwidth.to_le_bytes());
+        b[20..24].copy_from_slice(&self.dsp_cutoff.to_le_bytes());
+        b[24] = self.format as u8;
+        for (c, d) in self.divisors.iter().enumerate() {
+            b[25 + c / 4] |= (d.trailing_zeros().min(3) as u8) << (2 * (c % 4));
+        }
+        b[41..50].copy_from_slice(&self.signal);
+        b[50] = self.encoding as u8;
+        b[51] = self.bit_depth.get();
+        b[52..54].copy_from_slice(&self.envelope_rate.to_le_bytes());
+        b
+    }
+    /// Decode the configuration.
+    pub fn decode(b: &[u8]) -> Result<Self, DecodeError> {
+        let b: &[u8; CONFIG_SIZE] = b
+            .get(..CONFIG_SIZE)
+            .and_then(|b| b.try_into().ok())
+            .ok_or(DecodeError::TooShort)?;
+        let f32_at = |i: usize| f32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        let mut divisors = [1; CONFIG_CHANNELS];
+        for (c, d) in divisors.iter_mut().enumerate() {
+            *d = 1 << ((b[25 + c / 4] >> (2 * (c % 4))) & 3);
+        }
+        let mut signal = [0; SIGNAL_SIZE];
+        signal.copy_from_slice(&b[41..50]);
+        Ok(Self {
+            channel_mask: u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]),
+            sample_rate: u32::from_le_bytes([b[8], b[9], b[10], b[11]]),
+            upper_bandwidth: f32_at(12),
+            lower_bandwidth: f32_at(16),
+            dsp_cutoff: f32_at(20),
+            format: b[24].try_into()?,
+            divisors,
+            signal,
+            encoding: b[50].try_into()?,
+            bit_depth: b[51].try_into()?,
+            envelope_rate: u16::from_le_bytes([b[52], b[53]]),
+        })
+    }
+}
+
+/// Capacitor used to inject the impedance test current.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImpedanceScale {
+    /// 0.1pF, for high impedances.
+    Small = 0,
+    /// 1pF.
+    Medium = 1,
+    /// 10pF, for low impedances.
+    Large = 2,
+}
+
+impl TryFrom<u8> for ImpedanceScale {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => Self::Small,
+            1 => Self::Medium,
+            2 => Self::Large,
+            v => return Err(DecodeError::UnknownScale(v)),
+        })
+    }
+}
+
+/// Parameters of an impedance measurement as sent by the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImpedanceParams {
+    /// Requested test frequency in Hz. The realised frequency is given in the report.
+    pub frequency: f32,
+    /// Capacitor used to inject the test current.
+    pub scale: ImpedanceScale,
+    /// Number of periods of the test signal to average over for each channel,
+    /// 1 to [`MAX_IMPEDANCE_PERIODS`].
+    pub periods: u16,
+}
+
+impl ImpedanceParams {
+    /// Encode the parameters.
+    pub fn encode(&self) -> [u8; IMPEDANCE_PARAMS_SIZE] {
+        let mut b = [0; IMPEDANCE_PARAMS_SIZE];
+        b[0..4].copy_from_slice(&self.frequency.to_le_bytes());
+        b[4] = self.scale as u8;
+        b[6..8].copy_from_slice(&self.periods.to_le_bytes());
+        b
+    }
+    /// Decode the parameters.
+    /// The frequency must be positive and finite, whether the brain interface can generate it
+    /// is only known once it measures.
+    pub fn decode(b: &[u8]) -> Result<Self, DecodeError> {
+        let b = b
+            .get(..IMPEDANCE_PARAMS_SIZE)
+            .ok_or(DecodeError::TooShort)?;
+        let params = Self {
+            frequency: f32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            scale: b[4].try_into()?,
+            periods: u16::from_le_bytes([b[6], b[7]]),
+        };
+        let frequency_valid = params.frequency.is_finite() && params.frequency > 0.0;
+        if !frequency_valid || !(1..=MAX_IMPEDANCE_PERIODS).contains(&params.periods) {
+            return Err(DecodeError::InvalidParameter);
+        }
+        Ok(params)
+    }
+}
+
+/// A command with its parameters.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// Start recording with the current configuration.
+    Start,
+    /// Stop recording. The connection stays open.
+    Stop,
+    /// Replace the configuration. A running recording restarts with it.
+    SetConfig(RecordingConfig),
+    /// Report whether the brain interface is recording.
+    Status,
+    /// Answer with the same token.
+    Ping(u32),
+    /// Set the current time of the timestamps in µs.
+    SetTime(u64),
+    /// Mark the event with the given number.
+    MarkEvent(u32),
+    /// Measure the electrode impedances of the configured channels and send the report.
+    /// A running recording is paused during the measurement.
+    MeasureImpedance(ImpedanceParams),
+}
+
+/// A command together with the tag of the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Request {
+    /// Number chosen by the host, repeated in the response.
+    pub tag: u8,
+    /// The command.
+    pub command: Command,
+}
+
+/// A command that could not be decoded, with the response it needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Rejection {
+    /// The opcode, if it could be read.
+    pub opcode: u8,
+    /// The tag, if it could be read.
+    pub tag: u8,
+    /// Why the command was rejected.
+    pub result: ResultCode,
+}
+
+impl Command {
+    /// The opcode of the command.
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            Self::Start => Opcode::Start,
+            Self::Stop => Opcode::Stop,
+            Self::SetConfig(_) => Opcode::SetConfig,
+            Self::Status => Opcode::Status,
+            Self::Ping(_) => Opcode::Ping,
+            Self::SetTime(_) => Opcode::SetTime,
+            Self::MarkEvent(_) => Opcode::MarkEvent,
+            Self::MeasureImpedance(_) => Opcode::MeasureImpedance,
+        }
+    }
+    /// Encode the command with `tag` into `b`. Returns the length of the command.
+    pub fn encode(&self, tag: u8, b: &mut [u8; MAX_COMMAND_SIZE]) -> usize {
+        b[0..2].copy_from_slice(&MAGIC);
+        b[2] = VERSION;
+        b[3] = self.opcode() as u8;
+        b[4] = tag;
+        let params = &mut b[COMMAND_HEADER_SIZE..];
+        let n = match self {
+            Self::Start | Self::Stop | Self::Status => 0,
+            Self::SetConfig(config) => {
+                params[..CONFIG_SIZE].copy_from_slice(&config.encode());
+                CONFIG_SIZE
+            }
+            Self::Ping(v) | Self::MarkEvent(v) => {
+                params[..4].copy_from_slice(&v.to_le_bytes());
+                4
+            }
+            Self::SetTime(t) => {
+                params[..8].copy_from_slice(&t.to_le_bytes());
+                8
+            }
+            Self::MeasureImpedance(p) => {
+                params[..IMPEDANCE_PARAMS_SIZE].copy_from_slice(&p.encode());
+                IMPEDANCE_PARAMS_SIZE
+            }
+        };
+        COMMAND_HEADER_SIZE + n
+    }
+    /// Decode a command.
+    pub fn decode(b: &[u8]) -> Result<Request, Rejection> {
+        let reject = |result| Rejection {
+            opcode: b.get(3).copied().unwrap_or(0),
+            tag: b.get(4).copied().unwrap_or(0),
+            result,
+        };
+        if b.len() < COMMAND_HEADER_SIZE || b[0..2] != MAGIC {
+            return Err(reject(ResultCode::Malformed));
+        }
+        if b[2] != VERSION {
+            return Err(reject(ResultCode::UnsupportedVersion));
+        }
+        let params = &b[COMMAND_HEADER_SIZE..];
+        let malformed = || reject(ResultCode::Malformed);
+        let u32_param = || {
+            params
+                .get(..4)
+                .map(|p| u32::from_le_bytes([p[0], p[1], p[2], p[3]]))
+                .ok_or_else(malformed)
+        };
+        let command = match b[3] {
+            1 => Self::Start,
+            2 => Self::Stop,
+            3 => Self::SetConfig(RecordingConfig::decode(params).map_err(|_| malformed())?),
+            4 => Self::Status,
+            5 => Self::Ping(u32_param()?),
+            6 => Self::SetTime(
+                params
+                    .get(..8)
+                    .map(|p| u64::from_le_bytes([p[0], p[1], p[2], p[3], p[4], p[5], p[6], p[7]]))
+                    .ok_or_else(malformed)?,
+            ),
+            7 => Self::MarkEvent(u32_param()?),
+            8 => Self::MeasureImpedance(ImpedanceParams::decode(params).map_err(|_| malformed())?),
+            _ => return Err(reject(ResultCode::UnknownCommand)),
+        };
+        Ok(Request { tag: b[4], command })
+    }
+}
+
+/// Fields at the start of the payload of a response.
+/// Depending on the command they are followed by data:
+/// a [`DeviceStatus`] for [`Command::Status`], the token for [`Command::Ping`]
+/// and an [`EventMark`] for [`Command::MarkEvent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Response {
+    /// Opcode of the command.
+    pub opcode: u8,
+    /// Tag of the command.
+    pub tag: u8,
+    /// Outcome of the command.
+    pub result: ResultCode,
+}
+
+impl Response {
+    /// The response to `request` with `result`.
+    pub fn to(request: &Request, result: ResultCode) -> Self {
+        Self {
+            opcode: request.command.opcode() as u8,
+            tag: request.tag,
+            result,
+        }
+    }
+    /// Encode the fields.
+    pub fn encode(&self) -> [u8; RESPONSE_SIZE] {
+        [self.opcode, self.tag, self.result as u8, 0]
+    }
+    /// Decode the fields from the payload of a response.
+    /// Returns the fields and the data behind them.
+    pub fn decode(payload: &[u8]) -> Result<(Self, &[u8]), DecodeError> {
+        if payload.len() < RESPONSE_SIZE {
+            return Err(DecodeError::TooShort);
+        }
+        let response = Self {
+            opcode: payload[0],
+            tag: payload[1],
+            result: payload[2].try_into()?,
+        };
+        Ok((response, &payload[RESPONSE_SIZE..]))
+    }
+}
+
+impl From<Rejection> for Response {
+    fn from(r: Rejection) -> Self {
+        Self {
+            opcode: r.opcode,
+            tag: r.tag,
+            result: r.result,
+        }
+    }
+}
+
+/// Answer to [`Command::Status`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceStatus {
+    /// A recording is running.
+    pub recording: bool,
+    /// Sample rate of the running recording in Hz as achieved by the timer, 0 if not recording.
+    /// It matches the sample period of the packets.
+    pub sample_rate: f32,
+    /// Estimated index of the frame being sampled.
+    pub frame: u64,
+}
+
+impl DeviceStatus {
+    /// Size of the encoded status.
+    pub const SIZE: usize = 16;
+    /// Encode the status.
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut b = [0; Self::SIZE];
+        b[0] = self.recording as u8;
+        b[4..8].copy_from_slice(&self.sample_rate.to_le_bytes());
+        b[8..16].copy_from_slice(&self.frame.to_le_bytes());
+        b
+    }
+    /// Decode the status from the data of a response.
+    pub fn decode(b: &[u8]) -> Result<Self, DecodeError> {
+        let b = b.get(..Self::SIZE).ok_or(DecodeError::TooShort)?;
+        Ok(Self {
+            recording: b[0] != 0,
+            sample_rate: f32::from_le_bytes([b[4], b[5], b[6], b[7]]),
+            frame: u64::from_le_bytes([b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]]),
+        })
+    }
+}
+
+/// Answer to [`Command::MarkEvent`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EventMark {
+    /// Number of the event.
+    pub id: u32,
+    /// Estimated index of the frame sampled when the command arrived.
+    pub frame: u64,
+}
+
+impl EventMark {
+    /// Size of the encoded event.
+    pub const SIZE: usize = 12;
+    /// Encode the event.
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut b = [0; Self::SIZE];
+        b[0..4].copy_from_slice(&self.id.to_le_bytes());
+        b[4..12].copy_from_slice(&self.frame.to_le_bytes());
+        b
+    }
+    /// Decode the event from the data of a response.
+    pub fn decode(b: &[u8]) -> Result<Self, DecodeError> {
+        let b = b.get(..Self::SIZE).ok_or(DecodeError::TooShort)?;
+        Ok(Self {
+            id: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            frame: u64::from_le_bytes([b[4], b[5], b[6], b[7], b[8], b[9], b[10], b[11]]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RecordingConfig {
+        let mut divisors = [1; CONFIG_CHANNELS];
+        divisors[3] = 2;
+        divisors[17] = 8;
+        divisors[63] = 4;
+        RecordingConfig {
+            channel_mask: 0x8000_0000_0002_0008,
+            sample_rate: 20_000,
+            upper_bandwidth: 7500.0,
+            lower_bandwidth: 0.5,
+            dsp_cutoff: 0.0,
+            format: SampleFormat::TwosComplement,
+            divisors,
+            signal: [3, 0, 0, 0x7a, 0x44, 0, 0, 0x20, 0x41],
+            encoding: SampleEncoding::Rice,
+            bit_depth: BitDepth::try_from(12).unwrap(),
+            envelope_rate: 50,
+        }
+    }
+
+    fn round_trip(command: Command) -> Result<Request, Rejection> {
+        let mut b = [0; MAX_COMMAND_SIZE];
+        let n = command.encode(42, &mut b);
+        Command::decode(&b[..n])
+    }
+
+    #[test]
+    fn commands_round_trip() {
+        let commands = [
+            Command::Start,
+            Command::Stop,
+            Command::SetConfig(config()),
+            Command::Status,
+            Command::Ping(0xdead_beef),
+            Command::SetTime(1_700_000_000_000_000),
+            Command::MarkEvent(7),
+            Command::MeasureImpedance(ImpedanceParams {
+                frequency: 1000.0,
+                scale: ImpedanceScale::Large,
+                periods: 20,
+            }),
+        ];
+        for command in commands {
+            assert_eq!(round_trip(command), Ok(Request { tag: 42, command }));
+        }
+        let mut b = [0; MAX_COMMAND_SIZE];
+        assert_eq!(Command::SetConfig(config()).encode(0, &mut b), 59);
+    }
+
+    #[test]
+    fn rejected_commands() {
+        let rejection = |opcode, tag, result| {
+            Err(Rejection {
+                opcode,
+                tag,
+                result,
+            })
+        };
+        assert_eq!(Command::decode(&[]), rejection(0, 0, ResultCode::Malformed));
+        assert_eq!(
+            Command::decode(&[1, 2, 3, 4]),
+            rejection(4, 0, ResultCode::Malformed)
+        );
+        assert_eq!(
+            Command::decode(b"BI\x01\x09\x05"),
+            rejection(9, 5, ResultCode::UnknownCommand)
+        );
+        assert_eq!(
+            Command::decode(b"BI\x02\x01\x05"),
+            rejection(1, 5, ResultCode::UnsupportedVersion)
+        );
+        assert_eq!(
+            Command::decode(b"BI\x01\x05\x06\x01\x02"),
+            rejection(5, 6, ResultCode::Malformed)
+        );
+        let mut b = [0; MAX_COMMAND_SIZE];
+        let n = Command::SetConfig(config()).encode(8, &mut b);
+        assert_eq!(
+            Command::decode(&b[..n - 1]),
+            rejection(3, 8, ResultCode::Malformed)
+        );
+    }
+
+    #[test]
+    fn invalid_impedance_params() {
+        let params = ImpedanceParams {
+            frequency: 250.0,
+            scale: ImpedanceScale::Small,
+            periods: MAX_IMPEDANCE_PERIODS,
+        };
+        assert_eq!(ImpedanceParams::decode(&params.encode()), Ok(params));
+        let mut b = params.encode();
+        b[4] = 3;
+        assert_eq!(
+            ImpedanceParams::decode(&b),
+            Err(DecodeError::UnknownScale(3))
+        );
+        for invalid in [
+            ImpedanceParams {
+                periods: 0,
+                ..params
+            },
+            ImpedanceParams {
+                periods: MAX_IMPEDANCE_PERIODS + 1,
+                ..params
+            },
+            ImpedanceParams {
+                frequency: 0.0,
+                ..params
+            },
+            ImpedanceParams {
+                frequency: f32::NAN,
+                ..params
+            },
+        ] {
+            assert_eq!(
+                ImpedanceParams::decode(&invalid.encode()),
+                Err(DecodeError::InvalidParameter)
+            );
+        }
+        assert_eq!(ImpedanceParams::decode(&b[..7]), Err(DecodeError::TooShort));
+        assert_eq!(
+            Command::decode(b"BI\x01\x08\x02\x00\x00\x7a\x44\x01\x00"),
+            Err(Rejection {
+                opcode: 8,
+                tag: 2,
+                result: ResultCode::Malformed
+            })
+        );
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let request = Request {
+            tag: 9,
+            command: Command::Status,
+        };
+        let status = DeviceStatus {
+            recording: true,
+            sample_rate: 2499.8,
+            frame: 123_456,
+        };
+        let mut payload = Response::to(&request, ResultCode::Ok).encode().to_vec();
+        payload.extend_from_slice(&status.encode());
+        let (response, data) = Response::decode(&payload).unwrap();
+        assert_eq!(response.opcode, Opcode::Status as u8);
+        assert_eq!(response.tag, 9);
+        assert_eq!(response.result, ResultCode::Ok);
+        assert_eq!(DeviceStatus::decode(data), Ok(status));
+        let event = EventMark { id: 3, frame: 99 };
+        assert_eq!(EventMark::decode(&event.encode()), Ok(event));
+        let rejected = Response::from(Command::decode(b"BI\x01\x63\x01").unwrap_err());
+        assert_eq!(Response::decode(&rejected.encode()).unwrap().0, rejected);
+    }
+}
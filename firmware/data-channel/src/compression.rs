@@ -0,0 +1,187 @@
+//! Lossless compression of the samples.
+//!
+//! Every sample is predicted by the sample of the same channel one cycle of the schedule earlier,
+//! `stride` samples before it, and only the difference is coded. The samples of the first cycle
+//! are predicted by the sample before them. The differences are mapped to unsigned numbers,
+//! small ones first, and Rice coded in blocks of [`BLOCK_SIZE`] with a parameter chosen per block
+//! from their mean. Differences too large for the parameter are escaped and stored with 16 bits.
+//!
+//! The encoder only needs shifts and additions per sample and writes in place, so it fits the
+//! Cortex-M4 of the brain interface.
+
+use crate::{
+    bits::{BitReader, BitWriter},
+    DecodeError,
+};
+
+/// Number of samples sharing a Rice parameter.
+pub const BLOCK_SIZE: usize = 16;
+/// Size of the sample count and the stride in front of the compressed samples.
+pub const COMPRESSED_HEADER_SIZE: usize = 4;
+/// Number of bits of the Rice parameter in front of every block.
+const PARAMETER_BITS: u32 = 4;
+/// Length of the unary prefix of an escaped sample.
+const ESCAPE: u32 = 16;
+
+/// Compress `samples`, which repeat their channels every `stride` samples, into `out`.
+/// Returns the number of bytes written, or `None` if they do not fit into `out`.
+/// With `out` as long as the plain samples they are only compressed if that saves space.
+pub fn compress(samples: &[u16], stride: usize, out: &mut [u8]) -> Option<usize> {
+    let count = u16::try_from(samples.len()).ok()?;
+    let stride = u16::try_from(stride.max(1)).ok()?;
+    if out.len() < COMPRESSED_HEADER_SIZE {
+        return None;
+    }
+    out[0..2].copy_from_slice(&count.to_le_bytes());
+    out[2..4].copy_from_slice(&stride.to_le_bytes());
+    let mut writer = BitWriter::new(&mut out[COMPRESSED_HEADER_SIZE..]);
+    let residual =
+        |i: usize| zigzag(samples[i].wrapping_sub(prediction(samples, stride as usize, i)));
+    for start in (0..samples.len()).step_by(BLOCK_SIZE) {
+        let block = start..(start + BLOCK_SIZE).min(samples.len());
+        let sum: u32 = block.clone().map(|i| residual(i) as u32).sum();
+        let k = parameter(sum, block.len());
+        writer.write(k, PARAMETER_BITS)?;
+        for i in block {
+            let r = residual(i) as u32;
+            let q = r >> k;
+            if q < ESCAPE {
+                writer.write((1 << q) - 1, q + 1)?;
+                writer.write(r & ((1 << k) - 1), k)?;
+            } else {
+                writer.write((1 << ESCAPE) - 1, ESCAPE)?;
+                writer.write(r, 16)?;
+            }
+        }
+    }
+    Some(COMPRESSED_HEADER_SIZE + writer.finish()?)
+}
+
+/// Number of samples compressed in `encoded`.
+pub fn compressed_len(encoded: &[u8]) -> Result<usize, DecodeError> {
+    match encoded {
+        [a, b, ..] => Ok(u16::from_le_bytes([*a, *b]) as usize),
+        _ => Err(DecodeError::TooShort),
+    }
+}
+
+/// Decompress the samples of [`compress`] into `out`. Returns the number of samples.
+/// Panics if `out` is shorter than [`compressed_len`].
+pub fn decompress(encoded: &[u8], out: &mut [u16]) -> Result<usize, DecodeError> {
+    if encoded.len() < COMPRESSED_HEADER_SIZE {
+        return Err(DecodeError::TooShort);
+    }
+    let count = compressed_len(encoded)?;
+    let stride = (u16::from_le_bytes([encoded[2], encoded[3]]) as usize).max(1);
+    let out = &mut out[..count];
+    let mut reader = BitReader::new(&encoded[COMPRESSED_HEADER_SIZE..]);
+    for start in (0..count).step_by(BLOCK_SIZE) {
+        let k = reader.read(PARAMETER_BITS)?;
+        for i in start..(start + BLOCK_SIZE).min(count) {
+            let mut q = 0;
+            while q < ESCAPE && reader.bit()? == 1 {
+                q += 1;
+            }
+            let r = if q == ESCAPE {
+                reader.read(16)?
+            } else {
+                q << k | reader.read(k)?
+            };
+            out[i] = prediction(out, stride, i).wrapping_add(unzigzag(r as u16));
+        }
+    }
+    Ok(count)
+}
+
+/// The prediction of sample `i` from the samples before it.
+fn prediction(samples: &[u16], stride: usize, i: usize) -> u16 {
+    match i.checked_sub(stride).or(i.checked_sub(1)) {
+        Some(j) => samples[j],
+        None => 0,
+    }
+}
+
+/// Map a difference to an unsigned number: 0, -1, 1, -2, 2 and so on become 0, 1, 2, 3, 4.
+fn zigzag(difference: u16) -> u16 {
+    let d = difference as i16;
+    ((d << 1) ^ (d >> 15)) as u16
+}
+
+/// Inverse of [`zigzag`].
+fn unzigzag(z: u16) -> u16 {
+    (z >> 1) ^ (z & 1).wrapping_neg()
+}
+
+/// Rice parameter for `n` numbers with the sum `sum`, the position of the highest bit of their mean.
+fn parameter(sum: u32, n: usize) -> u32 {
+    let mean = sum / n as u32;
+    (u32::BITS - 1).saturating_sub(mean.leading_zeros()).min(15)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noisy sines around 32768 on `channels` channels.
+    fn recording(channels: usize, frames: usize) -> Vec<u16> {
+        let mut noise = 12345u32;
+        (0..channels * frames)
+            .map(|i| {
+                noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let (f, c) = (i / channels, i % channels);
+                let sine = (f as f32 * 0.02 * (c + 1) as f32).sin() * 500.0;
+                (32768.0 + sine) as u16 + (noise >> 28) as u16
+            })
+            .collect()
+    }
+
+    fn round_trip(samples: &[u16], stride: usize, out: &mut [u8]) -> Option<Vec<u16>> {
+        let n = compress(samples, stride, out)?;
+        let mut decoded = vec![0; compressed_len(&out[..n]).unwrap()];
+        assert_eq!(decompress(&out[..n], &mut decoded), Ok(samples.len()));
+        Some(decoded)
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let samples = recording(8, 100);
+        let mut out = [0; 1600];
+        assert_eq!(round_trip(&samples, 8, &mut out).unwrap(), samples);
+        let n = compress(&samples, 8, &mut out).unwrap();
+        assert!(n < samples.len(), "{n} bytes for {} samples", samples.len());
+        for samples in [vec![], vec![7], vec![0, 65535, 0, 65535, 1, 32768, 32767]] {
+            assert_eq!(round_trip(&samples, 2, &mut out).unwrap(), samples);
+        }
+    }
+
+    #[test]
+    fn incompressible_samples() {
+        let mut noise = 1u32;
+        let samples: Vec<u16> = (0..200)
+            .map(|_| {
+                noise = noise.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (noise >> 16) as u16
+            })
+            .collect();
+        let mut out = [0; 400];
+        assert_eq!(compress(&samples, 4, &mut out), None);
+        let mut out = [0; 1000];
+        assert_eq!(round_trip(&samples, 4, &mut out).unwrap(), samples);
+    }
+
+    #[test]
+    fn truncated_samples() {
+        let samples = recording(4, 20);
+        let mut out = [0; 160];
+        let n = compress(&samples, 4, &mut out).unwrap();
+        let mut decoded = vec![0; samples.len()];
+        assert_eq!(
+            decompress(&out[..n - 1], &mut decoded),
+            Err(DecodeError::TooShort)
+        );
+        assert_eq!(
+            decompress(&out[..3], &mut decoded),
+            Err(DecodeError::TooShort)
+        );
+    }
+}
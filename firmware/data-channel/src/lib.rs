@@ -1,11 +1,32 @@
-#![no_std]
+//! Packets of the data channel between the brain interface and the dongle.
+//!
+//! Without the default `softdevice` feature only the [`Pool`] and the codecs of the packets,
+//! commands and compressed or packed samples are built, so host tools can decode the packets with the
+//! `std` feature.
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 
+#[cfg(feature = "softdevice")]
 extern crate alloc;
 
+mod codec;
+pub use codec::*;
+mod command;
+pub use command::*;
+mod bits;
+mod compression;
+pub use compression::*;
+mod packing;
+pub use packing::*;
+#[cfg(feature = "softdevice")]
 mod packet;
+#[cfg(feature = "softdevice")]
 pub use packet::*;
+#[cfg(feature = "softdevice")]
 mod l2cap_error;
+#[cfg(feature = "softdevice")]
 pub use l2cap_error::*;
+mod pool;
+pub use pool::*;
 
 pub const PSM: u16 = 0x2349;
 pub const QUEUE_SIZE: u8 = 200;
@@ -1,9 +1,10 @@
 use alloc::alloc::{alloc, dealloc};
 use core::{
     alloc::Layout,
+    marker::PhantomData,
     mem::ManuallyDrop,
     ops::{Deref, DerefMut},
-    ptr::{copy_nonoverlapping, NonNull},
+    ptr::{copy_nonoverlapping, write_bytes, NonNull},
     slice,
 };
 use nrf_softdevice::ble::l2cap::Packet;
@@ -69,3 +70,108 @@ impl<const N: usize> Packet for BoxPacket<N> {
         Self { len, ptr }
     }
 }
+
+/// Static storage for [`PoolPacket`]s, usually backed by a [`Pool`](crate::Pool).
+pub trait PacketPool: 'static {
+    /// Size of each packet in bytes.
+    const MTU: usize;
+    /// Take a free block of [`Self::MTU`] bytes.
+    fn allocate() -> Option<NonNull<u8>>;
+    /// Return a block.
+    ///
+    /// # Safety
+    /// The block must have been taken with [`Self::allocate`] and must not be used afterwards.
+    unsafe fn free(ptr: NonNull<u8>);
+}
+
+/// A Packet for use with the L2CAP driver backed by a static pool.
+/// Unlike [`BoxPacket`] it never touches the heap.
+pub struct PoolPacket<P: PacketPool> {
+    len: usize,
+    ptr: NonNull<u8>,
+    _pool: PhantomData<P>,
+}
+
+impl<P: PacketPool> PoolPacket<P> {
+    /// Take a new empty packet from the pool.
+    pub fn new() -> Option<Self> {
+        P::allocate().map(|ptr| Self {
+            len: 0,
+            ptr,
+            _pool: PhantomData,
+        })
+    }
+    /// Append the data to the packet.
+    /// Panics if the data does not fit into the buffer space.
+    pub fn append(&mut self, data: &[u8]) {
+        let n = data.len();
+        assert!(self.len + n <= P::MTU);
+        unsafe {
+            copy_nonoverlapping(data.as_ptr(), self.ptr.as_ptr().add(self.len), n);
+        }
+        self.len += n;
+    }
+    /// Let `f` append up to `n` bytes in place and return how many it used.
+    /// `f` gets the next `n` bytes of the buffer space, zeroed.
+    /// Panics if the bytes do not fit into the buffer space.
+    pub fn append_with(&mut self, n: usize, f: impl FnOnce(&mut [u8]) -> usize) -> usize {
+        assert!(self.len + n <= P::MTU);
+        let space = unsafe {
+            let ptr = self.ptr.as_ptr().add(self.len);
+            write_bytes(ptr, 0, n);
+            slice::from_raw_parts_mut(ptr, n)
+        };
+        let used = f(space);
+        assert!(used <= n);
+        self.len += used;
+        used
+    }
+    /// Clear the packet and set its size to zero.
+    pub fn reset(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<P: PacketPool> defmt::Format for PoolPacket<P> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(f, "PoolPacket {{ len: {} }}", self.len)
+    }
+}
+
+impl<P: PacketPool> Drop for PoolPacket<P> {
+    fn drop(&mut self) {
+        unsafe { P::free(self.ptr) }
+    }
+}
+
+impl<P: PacketPool> Deref for PoolPacket<P> {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<P: PacketPool> DerefMut for PoolPacket<P> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl<P: PacketPool> Packet for PoolPacket<P> {
+    const MTU: usize = P::MTU;
+    fn allocate() -> Option<NonNull<u8>> {
+        P::allocate()
+    }
+    fn into_raw_parts(self) -> (NonNull<u8>, usize) {
+        let me = ManuallyDrop::new(self);
+        (me.ptr, me.len)
+    }
+    unsafe fn from_raw_parts(ptr: NonNull<u8>, len: usize) -> Self {
+        assert!(len <= P::MTU);
+        Self {
+            len,
+            ptr,
+            _pool: PhantomData,
+        }
+    }
+}
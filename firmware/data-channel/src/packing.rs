@@ -0,0 +1,128 @@
+//! Samples with a reduced bit depth.
+//!
+//! The samples keep their upper bits and are packed densely, so 10 bit samples take 10 bits of
+//! the packet. At 16 bits the packed samples are the same as the plain 16 bit words.
+//! Unpacking shifts the samples back, so offset and scale of the samples stay the same.
+
+use crate::{bits::BitWriter, DecodeError, SampleFormat};
+
+/// Supported bit depths of the samples.
+pub const BIT_DEPTHS: [u8; 4] = [10, 12, 14, 16];
+
+/// Number of upper bits kept of every sample, always one of [`BIT_DEPTHS`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BitDepth(u8);
+
+impl BitDepth {
+    /// All 16 bits of the samples.
+    pub const FULL: Self = Self(16);
+    /// Number of bits.
+    pub const fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for BitDepth {
+    type Error = DecodeError;
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if BIT_DEPTHS.contains(&value) {
+            Ok(Self(value))
+        } else {
+            Err(DecodeError::UnsupportedBitDepth(value))
+        }
+    }
+}
+
+/// Size in bytes of `count` samples packed with `bit_depth` bits.
+pub const fn packed_size(count: usize, bit_depth: u8) -> usize {
+    (count * bit_depth as usize).div_ceil(8)
+}
+
+/// Reduce the samples to their upper `bit_depth` bits.
+/// Samples in two's complement keep their sign, so they can still be compressed.
+pub fn reduce(samples: &mut [u16], bit_depth: u8, format: SampleFormat) {
+    let shift = 16 - bit_depth as u32;
+    for s in samples {
+        *s = match format {
+            SampleFormat::OffsetBinary => *s >> shift,
+            SampleFormat::TwosComplement => ((*s as i16) >> shift) as u16,
+        };
+    }
+}
+
+/// Pack the lowest `bit_depth` bits of every sample into `out`.
+/// Returns the number of bytes written, see [`packed_size`].
+/// Panics if they do not fit into `out`.
+pub fn pack(samples: &[u16], bit_depth: u8, out: &mut [u8]) -> usize {
+    let n = bit_depth as u32;
+    let mut writer = BitWriter::new(out);
+    for &s in samples {
+        writer.write(s as u32 & ((1 << n) - 1), n).unwrap();
+    }
+    writer.finish().unwrap()
+}
+
+/// The samples packed with `bit_depth` bits, shifted back to 16 bits.
+/// The padding at the end is ignored.
+pub fn unpack(encoded: &[u8], bit_depth: u8) -> impl Iterator<Item = u16> + '_ {
+    let n = bit_depth as usize;
+    (0..encoded.len() * 8 / n).map(move |i| {
+        let bit = i * n;
+        let word = (0..3).fold(0u32, |w, j| {
+            w | (encoded.get(bit / 8 + j).copied().unwrap_or(0) as u32) << (8 * j)
+        });
+        let sample = (word >> (bit % 8)) & ((1 << n) - 1);
+        (sample << (16 - n)) as u16
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_round_trip() {
+        let samples: Vec<u16> = (0..37).map(|i| (i * 1777) as u16).collect();
+        for bit_depth in BIT_DEPTHS {
+            let mut reduced = samples.clone();
+            reduce(&mut reduced, bit_depth, SampleFormat::OffsetBinary);
+            let mut out = [0; 74];
+            let n = pack(&reduced, bit_depth, &mut out);
+            assert_eq!(n, packed_size(samples.len(), bit_depth));
+            let mask = !0 << (16 - bit_depth);
+            let unpacked: Vec<u16> = unpack(&out[..n], bit_depth).collect();
+            let expected: Vec<u16> = samples.iter().map(|s| s & mask).collect();
+            assert_eq!(unpacked, expected, "{bit_depth} bits");
+        }
+        let mut out = [0; 74];
+        let n = pack(&samples, 16, &mut out);
+        let words: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(out[..n], words);
+    }
+
+    #[test]
+    fn bit_depth_is_checked() {
+        for bit_depth in BIT_DEPTHS {
+            assert_eq!(BitDepth::try_from(bit_depth).unwrap().get(), bit_depth);
+        }
+        for bit_depth in [0, 8, 11, 17, 255] {
+            assert_eq!(
+                BitDepth::try_from(bit_depth),
+                Err(DecodeError::UnsupportedBitDepth(bit_depth))
+            );
+        }
+    }
+
+    #[test]
+    fn signed_samples_keep_their_sign() {
+        let samples = [0u16, 0xffff, 0x8000, 0x7fff, 0xfff0, 0x0010];
+        let mut reduced = samples;
+        reduce(&mut reduced, 12, SampleFormat::TwosComplement);
+        assert_eq!(reduced, [0, 0xffff, 0xf800, 0x07ff, 0xffff, 0x0001]);
+        let mut out = [0; 9];
+        let n = pack(&reduced, 12, &mut out);
+        let unpacked: Vec<u16> = unpack(&out[..n], 12).collect();
+        assert_eq!(unpacked, [0, 0xfff0, 0x8000, 0x7ff0, 0xfff0, 0x0010]);
+    }
+}
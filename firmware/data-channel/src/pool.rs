@@ -0,0 +1,113 @@
+use core::{
+    cell::UnsafeCell,
+    mem::{size_of, MaybeUninit},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// A fixed number of statically allocated memory blocks.
+/// Taking and returning a block is lock free, so it can be done from an interrupt.
+pub struct Pool<T, const COUNT: usize> {
+    blocks: UnsafeCell<MaybeUninit<[T; COUNT]>>,
+    /// Bit `i` is set if block `i` is in use.
+    used: AtomicU32,
+}
+
+unsafe impl<T: Send, const COUNT: usize> Sync for Pool<T, COUNT> {}
+
+impl<T, const COUNT: usize> Default for Pool<T, COUNT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const COUNT: usize> Pool<T, COUNT> {
+    /// Create a pool with all blocks free. There can be at most 32 blocks.
+    pub const fn new() -> Self {
+        assert!(COUNT <= 32);
+        Self {
+            blocks: UnsafeCell::new(MaybeUninit::uninit()),
+            used: AtomicU32::new(0),
+        }
+    }
+    /// Take a free block. The block is not initialised.
+    /// Returns `None` if all blocks are in use.
+    pub fn allocate(&self) -> Option<NonNull<T>> {
+        let mut used = self.used.load(Ordering::Acquire);
+        loop {
+            let i = (!used).trailing_zeros() as usize;
+            if i >= COUNT {
+                return None;
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                used | (1 << i),
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let base = self.blocks.get() as *mut T;
+                    return NonNull::new(unsafe { base.add(i) });
+                }
+                Err(u) => used = u,
+            }
+        }
+    }
+    /// Return a block to the pool.
+    ///
+    /// # Safety
+    /// The block must have been taken from this pool and must not be used afterwards.
+    pub unsafe fn free(&self, ptr: NonNull<T>) {
+        let base = self.blocks.get() as usize;
+        let offset = ptr.as_ptr() as usize - base;
+        let i = offset / size_of::<T>();
+        debug_assert!(
+            i < COUNT && i * size_of::<T>() == offset,
+            "not a block of the pool"
+        );
+        let used = self.used.fetch_and(!(1 << i), Ordering::Release);
+        debug_assert!(used & (1 << i) != 0, "block {} freed twice", i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocate_until_empty() {
+        let pool = Pool::<u64, 5>::new();
+        let blocks: Vec<_> = (0..5).map(|_| pool.allocate().unwrap()).collect();
+        assert!(pool.allocate().is_none());
+        for (i, a) in blocks.iter().enumerate() {
+            for b in &blocks[i + 1..] {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn free_and_reuse() {
+        let pool = Pool::<u64, 3>::new();
+        let blocks: Vec<_> = (0..3).map(|_| pool.allocate().unwrap()).collect();
+        unsafe { pool.free(blocks[1]) };
+        assert_eq!(pool.allocate(), Some(blocks[1]));
+        assert!(pool.allocate().is_none());
+        for &block in &blocks {
+            unsafe { pool.free(block) };
+        }
+        assert_eq!(pool.allocate(), Some(blocks[0]));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "freed twice")]
+    fn double_free() {
+        let pool = Pool::<u64, 2>::new();
+        let block = pool.allocate().unwrap();
+        unsafe {
+            pool.free(block);
+            pool.free(block);
+        }
+    }
+}
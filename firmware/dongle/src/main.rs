@@ -2,6 +2,7 @@
 //!
 //! Scans for a brain interface and connects to it.
 //! The data is then send over USB to the connected PC.
+//! Commands from the PC are forwarded to the brain interface.
 
 #![no_std]
 #![no_main]
@@ -13,15 +14,17 @@ pub mod webusb;
 use core::cell::RefCell;
 
 use critical_section::Mutex;
-use data_channel::BoxPacket;
+use data_channel::{BoxPacket, Command, MAGIC, MAX_COMMAND_SIZE};
 use defmt::{info, unwrap, warn};
 use embassy_executor::Spawner;
+use embassy_futures::select::{select, Either};
 use embassy_nrf::{
     gpio::{Level, Output, OutputDrive},
     interrupt::{self, InterruptExt},
     usb::{vbus_detect::VbusDetect, Driver},
 };
-use embassy_time::{Duration, Instant, Timer};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use embassy_usb::{driver::EndpointError, msos, Builder, UsbDevice};
 use embedded_alloc::Heap;
 use nrf_softdevice::ble::{
@@ -150,15 +153,52 @@ fn usb_active() -> bool {
     })
 }
 
+/// A command from the PC, forwarded to the brain interface unchanged.
+struct CommandBuffer {
+    data: [u8; MAX_COMMAND_SIZE],
+    len: usize,
+}
+
+/// Commands waiting to be sent to the brain interface.
+static COMMANDS: Channel<CriticalSectionRawMutex, CommandBuffer, 4> = Channel::new();
+/// Tag of the commands the dongle sends on its own,
+/// so the PC can tell their responses apart from the responses to its commands.
+const DONGLE_TAG: u8 = 0xff;
+
+/// Receive messages from the USB. Every message counts as activity,
+/// the messages starting with [`MAGIC`] are queued as commands for the brain interface.
 #[embassy_executor::task]
 async fn usb_read_task(mut receiver: webusb::Receiver<'static, MyDriver>) -> ! {
     loop {
-        let mut data = [0u8; 20];
-        let _ = receiver.read(&mut data).await;
+        let mut data = [0u8; 64];
+        let n = match receiver.read(&mut data).await {
+            Ok(0) => continue,
+            Ok(n) => n,
+            Err(_) => {
+                // The endpoint is disabled, e.g. after a USB reset. Only data counts as activity.
+                receiver.wait_connection().await;
+                continue;
+            }
+        };
         critical_section::with(|cs| {
             let mut state = STATE.borrow_ref_mut(cs);
             state.last_usb_activity.replace(Instant::now());
         });
+        if !data[..n].starts_with(&MAGIC) {
+            continue;
+        }
+        if n > MAX_COMMAND_SIZE {
+            warn!("Command too long");
+            continue;
+        }
+        let mut command = CommandBuffer {
+            data: [0; MAX_COMMAND_SIZE],
+            len: n,
+        };
+        command.data[..n].copy_from_slice(&data[..n]);
+        if COMMANDS.try_send(command).is_err() {
+            warn!("Command lost");
+        }
     }
 }
 
@@ -180,25 +220,66 @@ impl From<EndpointError> for ConnectionError {
     }
 }
 
-/// Receive data from the L2CAP channel and forward it to the USB interface.
-async fn handle_connection(
-    channel: l2cap::Channel<MyPacket>,
-    usb_sender: &mut webusb::Sender<'static, MyDriver>,
+/// Send a command over the L2CAP channel.
+async fn send_command(
+    channel: &l2cap::Channel<MyPacket>,
+    command: &[u8],
 ) -> Result<(), ConnectionError> {
+    let mut packet = MyPacket::new().ok_or(ConnectionError {})?;
+    packet.append(command);
+    channel.tx(packet).await.map_err(|_| ConnectionError {})
+}
+
+/// Send the queued commands to the brain interface.
+/// A recording the host wants is stopped while the USB is inactive and started again once it
+/// is back. A Start or Stop of the host replaces the one of the dongle.
+async fn send_commands(channel: &l2cap::Channel<MyPacket>) -> Result<(), ConnectionError> {
+    // The brain interface starts recording when the dongle connects.
+    let mut host_recording = true;
     let mut stopped = false;
     loop {
-        if !usb_active() && !stopped {
-            channel
-                .tx(MyPacket::new().ok_or(ConnectionError {})?)
-                .await
-                .map_err(|_| ConnectionError {})?;
-            stopped = true;
+        if let Ok(command) = with_timeout(USB_TIMEOUT, COMMANDS.receive()).await {
+            let data = &command.data[..command.len];
+            match Command::decode(data).map(|r| r.command) {
+                Ok(Command::Start) => (host_recording, stopped) = (true, false),
+                Ok(Command::Stop) => (host_recording, stopped) = (false, false),
+                _ => {}
+            }
+            send_command(channel, data).await?;
         }
+        let command = match (usb_active(), stopped) {
+            (false, false) if host_recording => Command::Stop,
+            (true, true) => Command::Start,
+            _ => continue,
+        };
+        stopped = !stopped;
+        let mut data = [0; MAX_COMMAND_SIZE];
+        let n = command.encode(DONGLE_TAG, &mut data);
+        send_command(channel, &data[..n]).await?;
+    }
+}
+
+/// Receive data from the L2CAP channel and forward it to the USB interface.
+async fn forward_data(
+    channel: &l2cap::Channel<MyPacket>,
+    usb_sender: &mut webusb::Sender<'static, MyDriver>,
+) -> Result<(), ConnectionError> {
+    loop {
         let packet = channel.rx().await?;
         usb_sender.write(&packet).await?;
     }
 }
 
+/// Forward the data and the commands until the connection ends.
+async fn handle_connection(
+    channel: l2cap::Channel<MyPacket>,
+    usb_sender: &mut webusb::Sender<'static, MyDriver>,
+) -> Result<(), ConnectionError> {
+    match select(forward_data(&channel, usb_sender), send_commands(&channel)).await {
+        Either::First(result) | Either::Second(result) => result,
+    }
+}
+
 /// The main task.
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
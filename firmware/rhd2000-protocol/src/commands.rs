@@ -0,0 +1,92 @@
+//! Encoding of the 16 bit command words.
+//!
+//! The words are stored in the byte order of the DMA buffers. The SPI sends the bytes of
+//! each word in memory order, so the most significant byte of the command has to come first.
+
+// Functions to generate commands as u16.
+// Most of these intentionally use LE byte order with swapped bytes.
+/// Command to convert channel `c`.
+pub const fn convert_channel(c: u8) -> u16 {
+    (c as u16).to_le()
+}
+/// Command to read register `r`.
+pub const fn read_register(r: u8) -> u16 {
+    (r as u16 | 192).to_le()
+}
+/// Command to write register `r` with value `d`.
+pub const fn write_register(r: u8, d: u8) -> u16 {
+    (((d as u16) << 8) | (r as u16) | 128).to_le()
+}
+/// Command to start the calibration sequence.
+pub const fn start_calibration() -> u16 {
+    0b01010101u16
+}
+/// Dummy command. Reads register 40. Should contain the fixed value `b'I'`.
+pub const fn dummy_command() -> u16 {
+    read_register(40)
+}
+
+/// Convert a word between the byte order of the DMA buffers and the order on the wire.
+pub const fn swap_order(word: u16) -> u16 {
+    u16::from_be_bytes(word.to_ne_bytes())
+}
+
+/// A decoded command.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// Convert a channel.
+    Convert(u8),
+    /// Start the calibration of the ADC.
+    Calibrate,
+    /// Clear the calibration of the ADC.
+    Clear,
+    /// Write a value to a register.
+    Write(u8, u8),
+    /// Read a register.
+    Read(u8),
+    /// Any other bit pattern. Contains the command in wire order.
+    Unknown(u16),
+}
+
+impl Command {
+    /// Decode a word from a DMA buffer.
+    pub fn decode(word: u16) -> Self {
+        let command = swap_order(word);
+        let register = ((command >> 8) & 63) as u8;
+        match command >> 14 {
+            0b00 => Self::Convert(register),
+            0b10 => Self::Write(register, command as u8),
+            0b11 => Self::Read(register),
+            _ => match command {
+                0b01010101_00000000 => Self::Calibrate,
+                0b01101010_00000000 => Self::Clear,
+                _ => Self::Unknown(command),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_encoders() {
+        assert_eq!(Command::decode(convert_channel(17)), Command::Convert(17));
+        assert_eq!(Command::decode(read_register(63)), Command::Read(63));
+        assert_eq!(
+            Command::decode(write_register(4, 0xd5)),
+            Command::Write(4, 0xd5)
+        );
+        assert_eq!(Command::decode(start_calibration()), Command::Calibrate);
+        assert_eq!(Command::decode(dummy_command()), Command::Read(40));
+    }
+
+    #[test]
+    fn wire_order() {
+        // The first byte on the wire holds the command bits and the register.
+        assert_eq!(write_register(3, 0x12).to_ne_bytes(), [0b1000_0011, 0x12]);
+        assert_eq!(convert_channel(5).to_ne_bytes(), [5, 0]);
+        assert_eq!(swap_order(swap_order(0x1234)), 0x1234);
+    }
+}
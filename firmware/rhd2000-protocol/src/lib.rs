@@ -0,0 +1,21 @@
+//! Command protocol of the RHD2000 family of electrophysiology ADCs.
+//!
+//! The command encoders and the command sequences of the brain interface firmware live here,
+//! so they can be built and tested on the host. The [`Simulator`] is a software model of a chip
+//! that consumes the same command words.
+#![cfg_attr(not(test), no_std)]
+
+mod commands;
+pub use commands::*;
+mod registers;
+pub use registers::*;
+mod schedule;
+pub use schedule::*;
+mod sequence;
+pub use sequence::*;
+mod simulator;
+pub use simulator::*;
+mod units;
+pub use units::*;
+mod waveform;
+pub use waveform::*;
@@ -0,0 +1,419 @@
+//! Typed map of the configuration registers 0 to 17.
+//!
+//! Every bitfield of the registers has a named field. The map is converted to register values
+//! with [`RegisterMap::encode`] and values read back from the chip are parsed with
+//! [`RegisterMap::decode`]. Bits not covered by a field are always written as 0.
+
+use crate::{write_register, REGISTER_COUNT};
+
+/// Contents of the configuration registers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegisterMap {
+    // Register 0: ADC configuration and amplifier fast settle
+    /// ADC reference buffer bias, 2 bits.
+    pub adc_reference_bandwidth: u8,
+    /// Short the amplifier inputs to settle them quickly.
+    pub amp_fast_settle: bool,
+    /// Power the amplifier reference voltage.
+    pub amp_vref_enable: bool,
+    /// ADC comparator bias, 2 bits.
+    pub adc_comparator_bias: u8,
+    /// ADC comparator select, 2 bits.
+    pub adc_comparator_select: u8,
+    // Register 1: Supply sensor and ADC buffer bias current
+    /// Enable the supply voltage sensor.
+    pub vdd_sense_enable: bool,
+    /// ADC buffer bias current, 6 bits.
+    pub adc_buffer_bias: u8,
+    // Register 2: MUX bias current
+    /// MUX bias current, 6 bits.
+    pub mux_bias: u8,
+    // Register 3: MUX load, temperature sensor and auxiliary digital output
+    /// MUX load, 3 bits.
+    pub mux_load: u8,
+    /// Close the second temperature sensor switch.
+    pub temp_s2: bool,
+    /// Close the first temperature sensor switch.
+    pub temp_s1: bool,
+    /// Enable the temperature sensor.
+    pub temp_enable: bool,
+    /// Put the auxiliary digital output into high impedance.
+    pub digout_hiz: bool,
+    /// Level of the auxiliary digital output.
+    pub digout: bool,
+    // Register 4: ADC output format and DSP offset removal
+    /// Release MISO while CS is high.
+    pub weak_miso: bool,
+    /// Two's complement results.
+    pub twos_comp: bool,
+    /// Absolute value of the results.
+    pub abs_mode: bool,
+    /// Enable the DSP offset removal.
+    pub dsp_enable: bool,
+    /// Cutoff setting of the DSP offset removal, 4 bits.
+    pub dsp_cutoff: u8,
+    // Register 5: Impedance check control
+    /// Power the impedance check DAC.
+    pub zcheck_dac_power: bool,
+    /// Load the impedance check DAC.
+    pub zcheck_load: bool,
+    /// Capacitor used for the impedance check, 2 bits.
+    pub zcheck_scale: u8,
+    /// Connect all electrodes to the impedance check DAC.
+    pub zcheck_conn_all: bool,
+    /// Connect the negative inputs of bipolar amplifiers instead of the positive ones.
+    pub zcheck_sel_pol: bool,
+    /// Enable the impedance check.
+    pub zcheck_enable: bool,
+    // Register 6: Impedance check DAC
+    /// Output of the impedance check DAC.
+    pub zcheck_dac: u8,
+    // Register 7: Impedance check amplifier select
+    /// Amplifier connected to the impedance check DAC, 6 bits.
+    pub zcheck_select: u8,
+    // Registers 8 to 13: Amplifier bandwidth and auxiliary inputs
+    /// Use an off-chip resistor for RH1.
+    pub offchip_rh1: bool,
+    /// RH1 DAC1, 6 bits.
+    pub rh1_dac1: u8,
+    /// Enable the ADC input of AUXIN1.
+    pub aux1_enable: bool,
+    /// RH1 DAC2, 5 bits.
+    pub rh1_dac2: u8,
+    /// Use an off-chip resistor for RH2.
+    pub offchip_rh2: bool,
+    /// RH2 DAC1, 6 bits.
+    pub rh2_dac1: u8,
+    /// Enable the ADC input of AUXIN2.
+    pub aux2_enable: bool,
+    /// RH2 DAC2, 5 bits.
+    pub rh2_dac2: u8,
+    /// Use an off-chip resistor for RL.
+    pub offchip_rl: bool,
+    /// RL DAC1, 7 bits.
+    pub rl_dac1: u8,
+    /// Enable the ADC input of AUXIN3.
+    pub aux3_enable: bool,
+    /// RL DAC3.
+    pub rl_dac3: bool,
+    /// RL DAC2, 6 bits.
+    pub rl_dac2: u8,
+    // Registers 14 to 17: Individual amplifier power
+    /// Bit `n` powers amplifier `n`.
+    pub amp_power: u32,
+}
+
+impl Default for RegisterMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A single bit at position `n`.
+const fn bit(value: bool, n: u8) -> u8 {
+    (value as u8) << n
+}
+
+/// Whether bit `n` of `value` is set.
+const fn is_set(value: u8, n: u8) -> bool {
+    value & (1 << n) != 0
+}
+
+impl RegisterMap {
+    /// The recommended biasing with everything else switched off.
+    pub const fn new() -> Self {
+        Self {
+            adc_reference_bandwidth: 3,
+            amp_fast_settle: false,
+            amp_vref_enable: true,
+            adc_comparator_bias: 3,
+            adc_comparator_select: 2,
+            vdd_sense_enable: false,
+            adc_buffer_bias: 8,
+            mux_bias: 32,
+            mux_load: 0,
+            temp_s2: false,
+            temp_s1: false,
+            temp_enable: false,
+            digout_hiz: false,
+            digout: false,
+            weak_miso: false,
+            twos_comp: false,
+            abs_mode: false,
+            dsp_enable: false,
+            dsp_cutoff: 0,
+            zcheck_dac_power: false,
+            zcheck_load: false,
+            zcheck_scale: 0,
+            zcheck_conn_all: false,
+            zcheck_sel_pol: false,
+            zcheck_enable: false,
+            zcheck_dac: 128,
+            zcheck_select: 0,
+            offchip_rh1: false,
+            rh1_dac1: 0,
+            aux1_enable: false,
+            rh1_dac2: 0,
+            offchip_rh2: false,
+            rh2_dac1: 0,
+            aux2_enable: false,
+            rh2_dac2: 0,
+            offchip_rl: false,
+            rl_dac1: 0,
+            aux3_enable: false,
+            rl_dac3: false,
+            rl_dac2: 0,
+            amp_power: 0,
+        }
+    }
+    /// Power the amplifiers in `mask`.
+    pub const fn amplifiers(self, mask: u32) -> Self {
+        Self {
+            amp_power: mask,
+            ..self
+        }
+    }
+    /// Short the amplifier inputs.
+    pub const fn fast_settle(self, enable: bool) -> Self {
+        Self {
+            amp_fast_settle: enable,
+            ..self
+        }
+    }
+    /// Select the output format and the DSP offset removal.
+    /// `dsp_cutoff` is the cutoff setting, or `None` to disable the offset removal.
+    pub const fn output(self, twos_comp: bool, dsp_cutoff: Option<u8>) -> Self {
+        let (dsp_enable, dsp_cutoff) = match dsp_cutoff {
+            Some(n) => (true, n),
+            None => (false, 0),
+        };
+        Self {
+            twos_comp,
+            dsp_enable,
+            dsp_cutoff,
+            ..self
+        }
+    }
+    /// Enable the auxiliary inputs and the supply and temperature sensors.
+    pub const fn sensors(self, enable: bool) -> Self {
+        Self {
+            aux1_enable: enable,
+            aux2_enable: enable,
+            aux3_enable: enable,
+            vdd_sense_enable: enable,
+            temp_enable: enable,
+            ..self
+        }
+    }
+    /// Set the temperature sensor switches.
+    pub const fn temperature_switches(self, s1: bool, s2: bool) -> Self {
+        Self {
+            temp_s1: s1,
+            temp_s2: s2,
+            ..self
+        }
+    }
+    /// Connect the powered impedance check DAC to `channel` using capacitor `scale`.
+    pub const fn impedance_check(self, channel: u8, scale: u8) -> Self {
+        Self {
+            zcheck_dac_power: true,
+            zcheck_enable: true,
+            zcheck_scale: scale,
+            zcheck_select: channel,
+            ..self
+        }
+    }
+    /// Set the bandwidth resistor DACs.
+    /// `rh1` and `rh2` are the values of DAC1 and DAC2, `rl` of DAC1, DAC2 and DAC3.
+    pub const fn bandwidth(self, rh1: (u8, u8), rh2: (u8, u8), rl: (u8, u8, bool)) -> Self {
+        Self {
+            rh1_dac1: rh1.0,
+            rh1_dac2: rh1.1,
+            rh2_dac1: rh2.0,
+            rh2_dac2: rh2.1,
+            rl_dac1: rl.0,
+            rl_dac2: rl.1,
+            rl_dac3: rl.2,
+            ..self
+        }
+    }
+    /// Switch off the amplifiers, their reference, the ADC biases, the sensors and the
+    /// impedance check DAC. The other settings are kept.
+    pub const fn power_down(self) -> Self {
+        Self {
+            amp_power: 0,
+            amp_vref_enable: false,
+            adc_comparator_bias: 0,
+            adc_buffer_bias: 0,
+            mux_bias: 0,
+            zcheck_dac_power: false,
+            zcheck_enable: false,
+            ..self.sensors(false)
+        }
+    }
+    /// Value of register `r`. Registers outside of the map are 0.
+    pub const fn register(&self, r: usize) -> u8 {
+        match r {
+            0 => {
+                (self.adc_reference_bandwidth & 3) << 6
+                    | bit(self.amp_fast_settle, 5)
+                    | bit(self.amp_vref_enable, 4)
+                    | (self.adc_comparator_bias & 3) << 2
+                    | (self.adc_comparator_select & 3)
+            }
+            1 => bit(self.vdd_sense_enable, 6) | (self.adc_buffer_bias & 63),
+            2 => self.mux_bias & 63,
+            3 => {
+                (self.mux_load & 7) << 5
+                    | bit(self.temp_s2, 4)
+                    | bit(self.temp_s1, 3)
+                    | bit(self.temp_enable, 2)
+                    | bit(self.digout_hiz, 1)
+                    | bit(self.digout, 0)
+            }
+            4 => {
+                bit(self.weak_miso, 7)
+                    | bit(self.twos_comp, 6)
+                    | bit(self.abs_mode, 5)
+                    | bit(self.dsp_enable, 4)
+                    | (self.dsp_cutoff & 15)
+            }
+            5 => {
+                bit(self.zcheck_dac_power, 6)
+                    | bit(self.zcheck_load, 5)
+                    | (self.zcheck_scale & 3) << 3
+                    | bit(self.zcheck_conn_all, 2)
+                    | bit(self.zcheck_sel_pol, 1)
+                    | bit(self.zcheck_enable, 0)
+            }
+            6 => self.zcheck_dac,
+            7 => self.zcheck_select & 63,
+            8 => bit(self.offchip_rh1, 7) | (self.rh1_dac1 & 63),
+            9 => bit(self.aux1_enable, 7) | (self.rh1_dac2 & 31),
+            10 => bit(self.offchip_rh2, 7) | (self.rh2_dac1 & 63),
+            11 => bit(self.aux2_enable, 7) | (self.rh2_dac2 & 31),
+            12 => bit(self.offchip_rl, 7) | (self.rl_dac1 & 127),
+            13 => bit(self.aux3_enable, 7) | bit(self.rl_dac3, 6) | (self.rl_dac2 & 63),
+            14..=17 => (self.amp_power >> (8 * (r - 14))) as u8,
+            _ => 0,
+        }
+    }
+    /// Values of all registers.
+    pub const fn encode(&self) -> [u8; REGISTER_COUNT] {
+        let mut values = [0; REGISTER_COUNT];
+        let mut r = 0;
+        while r < REGISTER_COUNT {
+            values[r] = self.register(r);
+            r += 1;
+        }
+        values
+    }
+    /// Parse the register values read back from a chip.
+    pub const fn decode(values: &[u8; REGISTER_COUNT]) -> Self {
+        let v = values;
+        Self {
+            adc_reference_bandwidth: v[0] >> 6,
+            amp_fast_settle: is_set(v[0], 5),
+            amp_vref_enable: is_set(v[0], 4),
+            adc_comparator_bias: (v[0] >> 2) & 3,
+            adc_comparator_select: v[0] & 3,
+            vdd_sense_enable: is_set(v[1], 6),
+            adc_buffer_bias: v[1] & 63,
+            mux_bias: v[2] & 63,
+            mux_load: v[3] >> 5,
+            temp_s2: is_set(v[3], 4),
+            temp_s1: is_set(v[3], 3),
+            temp_enable: is_set(v[3], 2),
+            digout_hiz: is_set(v[3], 1),
+            digout: is_set(v[3], 0),
+            weak_miso: is_set(v[4], 7),
+            twos_comp: is_set(v[4], 6),
+            abs_mode: is_set(v[4], 5),
+            dsp_enable: is_set(v[4], 4),
+            dsp_cutoff: v[4] & 15,
+            zcheck_dac_power: is_set(v[5], 6),
+            zcheck_load: is_set(v[5], 5),
+            zcheck_scale: (v[5] >> 3) & 3,
+            zcheck_conn_all: is_set(v[5], 2),
+            zcheck_sel_pol: is_set(v[5], 1),
+            zcheck_enable: is_set(v[5], 0),
+            zcheck_dac: v[6],
+            zcheck_select: v[7] & 63,
+            offchip_rh1: is_set(v[8], 7),
+            rh1_dac1: v[8] & 63,
+            aux1_enable: is_set(v[9], 7),
+            rh1_dac2: v[9] & 31,
+            offchip_rh2: is_set(v[10], 7),
+            rh2_dac1: v[10] & 63,
+            aux2_enable: is_set(v[11], 7),
+            rh2_dac2: v[11] & 31,
+            offchip_rl: is_set(v[12], 7),
+            rl_dac1: v[12] & 127,
+            aux3_enable: is_set(v[13], 7),
+            rl_dac3: is_set(v[13], 6),
+            rl_dac2: v[13] & 63,
+            amp_power: u32::from_le_bytes([v[14], v[15], v[16], v[17]]),
+        }
+    }
+    /// Command writing the map's value to register `r`.
+    pub const fn write_command(&self, r: usize) -> u16 {
+        write_register(r as u8, self.register(r))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{swap_order, Chip, Simulator, Waveform};
+
+    #[test]
+    fn default_biasing() {
+        let values = RegisterMap::new().encode();
+        assert_eq!(values[..8], [0b11011110, 8, 32, 0, 0, 0, 128, 0]);
+        assert!(values[8..].iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn round_trip() {
+        let map = RegisterMap::new()
+            .amplifiers(0x8001_0ff0)
+            .output(true, Some(9))
+            .sensors(true)
+            .temperature_switches(true, false)
+            .impedance_check(21, 3)
+            .bandwidth((30, 5), (43, 7), (99, 60, true));
+        let values = map.encode();
+        assert_eq!(values[3], 0b0000_1100);
+        assert_eq!(values[4], 0b0101_1001);
+        assert_eq!(values[5], 0b0101_1001);
+        assert_eq!(values[13], 0b1111_1100);
+        assert_eq!(values[14..], [0xf0, 0x0f, 0x01, 0x80]);
+        assert_eq!(RegisterMap::decode(&values), map);
+    }
+
+    #[test]
+    fn fields_are_masked() {
+        let map = RegisterMap {
+            adc_buffer_bias: 0xff,
+            zcheck_select: 0xff,
+            ..RegisterMap::new()
+        };
+        assert_eq!(map.register(1), 63);
+        assert_eq!(map.register(7), 63);
+        assert_eq!(map.register(18), 0);
+    }
+
+    #[test]
+    fn write_and_read_back() {
+        let map = RegisterMap::new().amplifiers(0xffff).fast_settle(true);
+        let mut chip = Simulator::new(Chip::RHD2216, Waveform::Constant(0));
+        for r in 0..REGISTER_COUNT {
+            chip.transfer(map.write_command(r));
+        }
+        let values: Vec<u8> = (0..REGISTER_COUNT as u8)
+            .map(|r| chip.register(r))
+            .collect();
+        assert_eq!(values, map.encode());
+        assert_eq!(swap_order(chip.transfer(0)) & 0xff00, 0xff00);
+    }
+}
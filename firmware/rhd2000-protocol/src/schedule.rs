@@ -0,0 +1,201 @@
+//! Readout schedules with a rate divisor for every channel.
+//!
+//! Every frame has the same number of conversion slots, the lanes. A channel with the
+//! divisor `d` is converted in every `d`th frame, always in the same lane, so its samples
+//! stay evenly spaced. Channels converted in different frames share a lane.
+//! The pattern repeats after `cycle` frames, the largest divisor.
+//!
+//! The divisors are powers of two. Placing the channels in the order of their divisors,
+//! each into the first lane and phase that is still free, then never leaves a gap that
+//! a later channel could have used.
+
+/// Largest rate divisor.
+pub const MAX_DIVISOR: usize = 8;
+/// Maximum number of conversion slots per frame.
+pub const MAX_LANES: usize = 32;
+/// Value of a slot without a conversion.
+pub const EMPTY_SLOT: u8 = 0xff;
+
+/// Reasons why a schedule cannot be built.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScheduleError {
+    /// A divisor is not a power of two up to [`MAX_DIVISOR`].
+    InvalidDivisor,
+    /// The channels need more than [`MAX_LANES`] slots per frame.
+    TooManyLanes,
+    /// No channel was given.
+    NoChannels,
+}
+
+/// The channel converted in each slot of the frames of one cycle.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Schedule {
+    /// Number of conversion slots per frame.
+    lanes: usize,
+    /// Number of frames after which the pattern repeats.
+    cycle: usize,
+    /// Channel of every lane in every frame of the cycle, or [`EMPTY_SLOT`].
+    slots: [[u8; MAX_DIVISOR]; MAX_LANES],
+}
+
+impl Schedule {
+    /// A schedule without any lanes.
+    pub const fn empty() -> Self {
+        Self {
+            lanes: 0,
+            cycle: 1,
+            slots: [[EMPTY_SLOT; MAX_DIVISOR]; MAX_LANES],
+        }
+    }
+    /// Convert all `channels` in every frame, in the given order.
+    pub fn uniform(channels: &[u8]) -> Result<Self, ScheduleError> {
+        Self::new(channels.iter().map(|&c| (c, 1)))
+    }
+    /// Build the schedule for pairs of channel and rate divisor.
+    /// Channels with the same divisor get the lanes in the given order.
+    pub fn new(channels: impl Iterator<Item = (u8, usize)> + Clone) -> Result<Self, ScheduleError> {
+        let mut cycle = 0;
+        for (_, divisor) in channels.clone() {
+            if !divisor.is_power_of_two() || divisor > MAX_DIVISOR {
+                return Err(ScheduleError::InvalidDivisor);
+            }
+            cycle = cycle.max(divisor);
+        }
+        if cycle == 0 {
+            return Err(ScheduleError::NoChannels);
+        }
+        let mut schedule = Self {
+            cycle,
+            ..Self::empty()
+        };
+        let mut divisor = 1;
+        while divisor <= cycle {
+            for (channel, _) in channels.clone().filter(|&(_, d)| d == divisor) {
+                schedule.place(channel, divisor)?;
+            }
+            divisor *= 2;
+        }
+        Ok(schedule)
+    }
+    /// Put `channel` into the first lane and phase where all its frames are free.
+    fn place(&mut self, channel: u8, divisor: usize) -> Result<(), ScheduleError> {
+        let cycle = self.cycle;
+        for lane in 0..MAX_LANES {
+            let frames = &mut self.slots[lane][..cycle];
+            let phase = (0..divisor).find(|&phase| {
+                frames
+                    .iter()
+                    .skip(phase)
+                    .step_by(divisor)
+                    .all(|&s| s == EMPTY_SLOT)
+            });
+            if let Some(phase) = phase {
+                for slot in frames.iter_mut().skip(phase).step_by(divisor) {
+                    *slot = channel;
+                }
+                self.lanes = self.lanes.max(lane + 1);
+                return Ok(());
+            }
+        }
+        Err(ScheduleError::TooManyLanes)
+    }
+    /// Number of conversion slots per frame.
+    pub fn lanes(&self) -> usize {
+        self.lanes
+    }
+    /// Number of frames after which the pattern repeats.
+    pub fn cycle(&self) -> usize {
+        self.cycle
+    }
+    /// The channel converted in `lane` of `frame`, if any.
+    pub fn channel(&self, frame: usize, lane: usize) -> Option<u8> {
+        match self.slots[lane][frame % self.cycle] {
+            EMPTY_SLOT => None,
+            c => Some(c),
+        }
+    }
+    /// The slots of one cycle, frame by frame.
+    /// Contains the channel number or [`EMPTY_SLOT`] for every lane.
+    pub fn table(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.cycle).flat_map(move |f| (0..self.lanes).map(move |l| self.slots[l][f]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dummy_command, fill_readout_commands, swap_order, Chip, Simulator, Waveform};
+
+    #[test]
+    fn uniform_keeps_the_order() {
+        let schedule = Schedule::uniform(&[4, 7, 2]).unwrap();
+        assert_eq!(schedule.lanes(), 3);
+        assert_eq!(schedule.cycle(), 1);
+        assert_eq!(schedule.table().collect::<Vec<_>>(), [4, 7, 2]);
+        assert_eq!(schedule.channel(5, 1), Some(7));
+    }
+
+    #[test]
+    fn slow_channels_share_lanes() {
+        // One fast channel and seven channels at an eighth of the rate.
+        let channels = [(0, 1)].into_iter().chain((1..8).map(|c| (c, 8)));
+        let schedule = Schedule::new(channels).unwrap();
+        assert_eq!(schedule.lanes(), 2);
+        assert_eq!(schedule.cycle(), 8);
+        let table: Vec<u8> = schedule.table().collect();
+        assert_eq!(table[..4], [0, 1, 0, 2]);
+        assert_eq!(table[14..], [0, EMPTY_SLOT]);
+    }
+
+    #[test]
+    fn packing_is_dense() {
+        let channels = [(0, 2), (1, 4), (2, 1), (3, 4), (4, 8), (5, 8)];
+        let schedule = Schedule::new(channels.into_iter()).unwrap();
+        // 1/2 + 1/4 + 1 + 1/4 + 1/8 + 1/8 = 2.25
+        assert_eq!(schedule.lanes(), 3);
+        let used = schedule.table().filter(|&s| s != EMPTY_SLOT).count();
+        assert_eq!(used, 18);
+    }
+
+    #[test]
+    fn invalid_schedules() {
+        let err = |c: &[(u8, usize)]| Schedule::new(c.iter().copied()).unwrap_err();
+        assert_eq!(err(&[(0, 3)]), ScheduleError::InvalidDivisor);
+        assert_eq!(err(&[(0, 16)]), ScheduleError::InvalidDivisor);
+        assert_eq!(err(&[(0, 0)]), ScheduleError::InvalidDivisor);
+        assert_eq!(err(&[]), ScheduleError::NoChannels);
+        let many: Vec<(u8, usize)> = (0..33).map(|c| (c, 1)).collect();
+        assert_eq!(err(&many), ScheduleError::TooManyLanes);
+    }
+
+    #[test]
+    fn conversions_are_evenly_spaced() {
+        let channels = [(3u8, 1), (5, 2), (6, 4), (9, 4), (12, 8)];
+        let schedule = Schedule::new(channels.into_iter()).unwrap();
+        let stride = schedule.lanes() + 2;
+        let frames = 64;
+        let mut tx = vec![0u16; frames * stride];
+        let mut rx = vec![0u16; frames * stride];
+        fill_readout_commands(&mut tx, &schedule, stride, |_, _| dummy_command());
+        let mut chip = Simulator::new(Chip::RHD2216, Waveform::Ramp);
+        chip.run(&tx, &mut rx);
+        for (channel, divisor) in channels {
+            assert_eq!(chip.conversions(channel), (frames / divisor) as u32);
+            let frames_of_channel: Vec<usize> = (0..frames)
+                .filter(|&f| (0..schedule.lanes()).any(|l| schedule.channel(f, l) == Some(channel)))
+                .collect();
+            assert!(frames_of_channel.windows(2).all(|w| w[1] - w[0] == divisor));
+        }
+        // Every slot returns the next sample of its channel.
+        let mut count = [0u32; 64];
+        for f in 0..frames - 1 {
+            for l in 0..schedule.lanes() {
+                if let Some(c) = schedule.channel(f, l) {
+                    let sample = swap_order(rx[f * stride + l + 2]);
+                    assert_eq!(sample, Waveform::Ramp.sample(c, count[c as usize]));
+                    count[c as usize] += 1;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! Command sequences for starting the chip and reading out the channels.
+
+use crate::{
+    convert_channel, dummy_command, read_register, start_calibration, RegisterMap, Schedule,
+};
+
+/// Number of configuration registers written at startup.
+pub const REGISTER_COUNT: usize = 18;
+/// Number of commands after which the response to a command arrives.
+pub const LATENCY: usize = 2;
+/// Index of the first register write in the startup buffer.
+pub const WRITE_START: usize = 10;
+/// Index of the first register read back in the startup buffer.
+pub const READBACK_START: usize = 30;
+/// Index of the first ROM read in the startup buffer.
+pub const ROM_START: usize = 50;
+/// The ROM registers read during startup.
+pub const ROM_REGISTERS: [u8; 9] = [40, 41, 42, 43, 44, 60, 61, 62, 63];
+/// Index of the calibration command in the startup buffer.
+/// Leaves at least 100µs after the register writes for the amplifiers to settle.
+pub const CALIBRATION_START: usize = 200;
+/// Contents of the ROM registers 40 to 44.
+pub const COMPANY_ID: &[u8; 5] = b"INTAN";
+
+/// Fill the buffer b with startup commands.
+/// Generates a sequence of commands that sets all registers, reads them back
+/// together with the ROM registers and then starts a calibration.
+pub fn fill_startup_commands(b: &mut [u16], registers: &RegisterMap) {
+    let writes = WRITE_START..WRITE_START + REGISTER_COUNT;
+    let readbacks = READBACK_START..READBACK_START + REGISTER_COUNT;
+    let rom = ROM_START..ROM_START + ROM_REGISTERS.len();
+    for (i, v) in b.iter_mut().enumerate() {
+        *v = match i {
+            // Write all the registers
+            i if writes.contains(&i) => registers.write_command(i - WRITE_START),
+            // Read them back
+            i if readbacks.contains(&i) => read_register((i - READBACK_START) as u8),
+            // Identify the chip
+            i if rom.contains(&i) => read_register(ROM_REGISTERS[i - ROM_START]),
+            CALIBRATION_START => start_calibration(),
+            _ => dummy_command(),
+        }
+    }
+}
+
+/// Commands putting the chip into its low-power state.
+/// All registers are written with `registers` powered down, so a later startup sequence
+/// restores the full configuration.
+pub const fn power_down_commands(registers: &RegisterMap) -> [u16; REGISTER_COUNT] {
+    let registers = registers.power_down();
+    let mut commands = [0; REGISTER_COUNT];
+    let mut r = 0;
+    while r < REGISTER_COUNT {
+        commands[r] = registers.write_command(r);
+        r += 1;
+    }
+    commands
+}
+
+/// Fill the buffer with commands to read out the channels of `schedule` repeatedly.
+/// Every frame of `stride` commands starts with the lanes of the schedule.
+/// The remaining slots of a frame are filled by `spare`, which gets the frame number
+/// and the index of the slot after the lanes.
+pub fn fill_readout_commands(
+    b: &mut [u16],
+    schedule: &Schedule,
+    stride: usize,
+    spare: impl Fn(usize, usize) -> u16,
+) {
+    let lanes = schedule.lanes();
+    for (i, v) in b.iter_mut().enumerate() {
+        let n = i % stride;
+        let frame = i / stride;
+        *v = if n < lanes {
+            schedule
+                .channel(frame, n)
+                .map_or_else(dummy_command, convert_channel)
+        } else {
+            spare(frame, n - lanes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{swap_order, write_register, Chip, Simulator, Waveform};
+
+    /// Registers as the firmware writes them for a few channels of an RHD2216.
+    const REGISTERS: RegisterMap = RegisterMap::new()
+        .amplifiers(0x0ff0)
+        .output(false, Some(12))
+        .sensors(true)
+        .bandwidth((8, 0), (11, 3), (16, 60, false));
+
+    #[test]
+    fn startup_sequence() {
+        let mut tx = [0u16; 256];
+        let mut rx = [0u16; 256];
+        fill_startup_commands(&mut tx, &REGISTERS);
+        let mut chip = Simulator::new(Chip::RHD2216, Waveform::Constant(0x8000));
+        chip.run(&tx, &mut rx);
+        let response = |i: usize| swap_order(rx[i + LATENCY]);
+        for (r, value) in REGISTERS.encode().into_iter().enumerate() {
+            assert_eq!(chip.register(r as u8), value);
+            assert_eq!(response(WRITE_START + r), 0xff00 | value as u16);
+            assert_eq!(response(READBACK_START + r), value as u16);
+        }
+        let rom: Vec<u8> = (0..ROM_REGISTERS.len())
+            .map(|n| response(ROM_START + n) as u8)
+            .collect();
+        assert_eq!(&rom[..5], COMPANY_ID);
+        assert_eq!(rom[6..], [1, 16, 2]);
+        assert_eq!(chip.conversions_during_calibration(), 0);
+        assert!(chip.calibrated());
+    }
+
+    #[test]
+    fn power_down_and_restart() {
+        let mut chip = Simulator::new(Chip::RHD2216, Waveform::Constant(0x8000));
+        for command in power_down_commands(&REGISTERS) {
+            chip.transfer(command);
+        }
+        let values: [u8; REGISTER_COUNT] = core::array::from_fn(|r| chip.register(r as u8));
+        let registers = RegisterMap::decode(&values);
+        assert_eq!(registers.amp_power, 0);
+        assert!(!registers.amp_vref_enable);
+        assert_eq!(registers.adc_buffer_bias, 0);
+        assert_eq!(registers.mux_bias, 0);
+        assert!(!registers.vdd_sense_enable && !registers.temp_enable);
+        assert_eq!(
+            registers.bandwidth((8, 0), (11, 3), (16, 60, false)),
+            registers
+        );
+        // The next startup sequence writes everything again.
+        let mut tx = [0u16; 256];
+        let mut rx = [0u16; 256];
+        fill_startup_commands(&mut tx, &REGISTERS);
+        chip.run(&tx, &mut rx);
+        for (r, value) in REGISTERS.encode().into_iter().enumerate() {
+            assert_eq!(chip.register(r as u8), value);
+        }
+    }
+
+    #[test]
+    fn readout_deinterleaves() {
+        let channels = [1u8, 5, 9, 12];
+        let stride = 7;
+        let frames = 40;
+        let mut tx = vec![0u16; frames * stride];
+        let mut rx = vec![0u16; frames * stride];
+        let schedule = Schedule::uniform(&channels).unwrap();
+        fill_readout_commands(&mut tx, &schedule, stride, |_, _| dummy_command());
+        let mut chip = Simulator::new(Chip::RHD2216, Waveform::Ramp);
+        chip.run(&tx, &mut rx);
+        for f in 0..frames {
+            for (i, &c) in channels.iter().enumerate() {
+                let sample = swap_order(rx[f * stride + i + LATENCY]);
+                assert_eq!(sample, Waveform::Ramp.sample(c, f as u32));
+            }
+        }
+        for &c in &channels {
+            assert_eq!(chip.conversions(c), frames as u32);
+        }
+    }
+
+    #[test]
+    fn readout_spare_slots() {
+        let schedule = Schedule::uniform(&[0, 1]).unwrap();
+        let mut tx = [0u16; 15];
+        fill_readout_commands(&mut tx, &schedule, 5, |frame, n| {
+            write_register(6, (frame * 10 + n) as u8)
+        });
+        assert_eq!(tx[0], convert_channel(0));
+        assert_eq!(tx[1], convert_channel(1));
+        assert_eq!(tx[2], write_register(6, 0));
+        assert_eq!(tx[4], write_register(6, 2));
+        assert_eq!(tx[11], convert_channel(1));
+        assert_eq!(tx[13], write_register(6, 21));
+    }
+}
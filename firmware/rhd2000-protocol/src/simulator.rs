@@ -0,0 +1,230 @@
+//! Software model of a chip of the RHD2000 family.
+//!
+//! The model executes the same command words the firmware puts into its DMA buffers
+//! and answers them like the chip does, two commands later. It keeps the register file,
+//! answers the ROM reads and returns a synthetic waveform for every conversion.
+//!
+//! The analog side is not modelled: the amplifier settings and the DSP offset removal
+//! have no effect on the samples, only the two's complement setting is applied.
+
+use crate::{swap_order, Command, Waveform, COMPANY_ID, LATENCY, REGISTER_COUNT};
+
+/// Number of commands the chip needs after the calibrate command.
+/// Conversions must not be requested during that time.
+const CALIBRATION_COMMANDS: usize = 9;
+/// Bit of register 4 selecting two's complement results.
+const TWOS_COMPLEMENT: u8 = 1 << 6;
+
+/// Contents of the ROM registers identifying a chip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chip {
+    /// Die revision in register 60.
+    pub die_revision: u8,
+    /// Whether the amplifiers are bipolar, register 61.
+    pub bipolar: bool,
+    /// Number of amplifiers in register 62.
+    pub amplifier_count: u8,
+    /// Chip ID in register 63.
+    pub chip_id: u8,
+}
+
+impl Chip {
+    /// An RHD2132 with 32 unipolar amplifiers.
+    pub const RHD2132: Self = Self {
+        die_revision: 1,
+        bipolar: false,
+        amplifier_count: 32,
+        chip_id: 1,
+    };
+    /// An RHD2216 with 16 bipolar amplifiers.
+    pub const RHD2216: Self = Self {
+        die_revision: 1,
+        bipolar: true,
+        amplifier_count: 16,
+        chip_id: 2,
+    };
+    /// An RHD2164 with 64 unipolar amplifiers.
+    pub const RHD2164: Self = Self {
+        die_revision: 1,
+        bipolar: false,
+        amplifier_count: 64,
+        chip_id: 4,
+    };
+}
+
+/// A simulated chip.
+pub struct Simulator {
+    /// ROM contents.
+    chip: Chip,
+    /// Signal on all channels.
+    waveform: Waveform,
+    /// Register file.
+    registers: [u8; REGISTER_COUNT],
+    /// Results still on their way to the MISO line, oldest first.
+    pipeline: [u16; LATENCY],
+    /// Number of conversions of each channel.
+    conversions: [u32; 64],
+    /// Number of commands left until the calibration is finished.
+    calibrating: usize,
+    /// A calibration has been completed.
+    calibrated: bool,
+    /// Number of conversions requested during a calibration.
+    conversions_during_calibration: u32,
+}
+
+impl Simulator {
+    /// Create a chip with all registers cleared.
+    pub fn new(chip: Chip, waveform: Waveform) -> Self {
+        Self {
+            chip,
+            waveform,
+            registers: [0; REGISTER_COUNT],
+            pipeline: [0; LATENCY],
+            conversions: [0; 64],
+            calibrating: 0,
+            calibrated: false,
+            conversions_during_calibration: 0,
+        }
+    }
+    /// Transfer one command word and return the word received at the same time.
+    /// Both words are in the byte order of the DMA buffers.
+    pub fn transfer(&mut self, word: u16) -> u16 {
+        let result = self.execute(Command::decode(word));
+        let received = self.pipeline[0];
+        self.pipeline.rotate_left(1);
+        self.pipeline[LATENCY - 1] = result;
+        swap_order(received)
+    }
+    /// Transfer all commands of `tx` and store the received words in `rx`,
+    /// like the DMA in array list mode.
+    pub fn run(&mut self, tx: &[u16], rx: &mut [u16]) {
+        for (&command, response) in tx.iter().zip(rx) {
+            *response = self.transfer(command);
+        }
+    }
+    /// Current value of a register.
+    pub fn register(&self, r: u8) -> u8 {
+        self.read(r) as u8
+    }
+    /// Number of conversions of `channel` so far.
+    pub fn conversions(&self, channel: u8) -> u32 {
+        self.conversions[channel as usize & 63]
+    }
+    /// Whether a calibration has been completed.
+    pub fn calibrated(&self) -> bool {
+        self.calibrated
+    }
+    /// Number of conversions that were requested while the chip was calibrating.
+    /// Their results are not valid on a real chip.
+    pub fn conversions_during_calibration(&self) -> u32 {
+        self.conversions_during_calibration
+    }
+    /// Result of reading register `r` in wire order.
+    fn read(&self, r: u8) -> u16 {
+        let r = r as usize;
+        let value = match r {
+            r if r < REGISTER_COUNT => self.registers[r],
+            r if (40..40 + COMPANY_ID.len()).contains(&r) => COMPANY_ID[r - 40],
+            60 => self.chip.die_revision,
+            61 => self.chip.bipolar as u8,
+            62 => self.chip.amplifier_count,
+            63 => self.chip.chip_id,
+            _ => 0,
+        };
+        value as u16
+    }
+    /// Execute a command and return its result in wire order.
+    fn execute(&mut self, command: Command) -> u16 {
+        let calibrating = self.calibrating > 0;
+        if calibrating {
+            self.calibrating -= 1;
+            self.calibrated = self.calibrating == 0;
+        }
+        match command {
+            Command::Convert(c) => {
+                if calibrating {
+                    self.conversions_during_calibration += 1;
+                }
+                let n = &mut self.conversions[c as usize];
+                let sample = self.waveform.sample(c, *n);
+                *n += 1;
+                if self.registers[4] & TWOS_COMPLEMENT != 0 {
+                    sample ^ 0x8000
+                } else {
+                    sample
+                }
+            }
+            Command::Calibrate => {
+                self.calibrating = CALIBRATION_COMMANDS;
+                self.calibrated = false;
+                0
+            }
+            Command::Clear => {
+                self.calibrated = false;
+                0
+            }
+            Command::Write(r, d) => {
+                if let Some(register) = self.registers.get_mut(r as usize) {
+                    *register = d;
+                }
+                0xff00 | d as u16
+            }
+            Command::Read(r) => self.read(r),
+            Command::Unknown(_) => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{convert_channel, dummy_command, read_register, start_calibration, write_register};
+
+    #[test]
+    fn responses_arrive_two_commands_later() {
+        let mut chip = Simulator::new(Chip::RHD2132, Waveform::Constant(1234));
+        let tx = [
+            write_register(7, 42),
+            read_register(7),
+            read_register(63),
+            dummy_command(),
+            dummy_command(),
+        ];
+        let mut rx = [0u16; 5];
+        chip.run(&tx, &mut rx);
+        let rx = rx.map(swap_order);
+        assert_eq!(rx, [0, 0, 0xff00 | 42, 42, 1]);
+    }
+
+    #[test]
+    fn twos_complement() {
+        let mut chip = Simulator::new(Chip::RHD2216, Waveform::Constant(0x8010));
+        let tx = [
+            convert_channel(3),
+            write_register(4, TWOS_COMPLEMENT),
+            convert_channel(3),
+            dummy_command(),
+            dummy_command(),
+        ];
+        let mut rx = [0u16; 5];
+        chip.run(&tx, &mut rx);
+        assert_eq!(swap_order(rx[2]), 0x8010);
+        assert_eq!(swap_order(rx[4]) as i16, 0x10);
+    }
+
+    #[test]
+    fn conversions_during_calibration() {
+        let mut chip = Simulator::new(Chip::RHD2216, Waveform::Ramp);
+        chip.transfer(start_calibration());
+        for _ in 0..CALIBRATION_COMMANDS {
+            chip.transfer(dummy_command());
+        }
+        chip.transfer(convert_channel(0));
+        assert!(chip.calibrated());
+        assert_eq!(chip.conversions_during_calibration(), 0);
+        chip.transfer(start_calibration());
+        chip.transfer(convert_channel(0));
+        assert!(!chip.calibrated());
+        assert_eq!(chip.conversions_during_calibration(), 1);
+    }
+}
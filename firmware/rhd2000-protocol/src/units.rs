@@ -0,0 +1,44 @@
+//! Scale factors of the ADC results from the RHD2000 datasheet.
+//!
+//! All channels are converted by the same 16 bit ADC, but the amplifiers, the auxiliary
+//! inputs and the sensors reach it with different gains.
+
+/// Input voltage of one LSB of an amplifier channel in V.
+pub const AMPLIFIER_LSB: f32 = 0.195e-6;
+/// Result of an amplifier channel at 0V in offset binary.
+/// In two's complement 0V is 0.
+pub const AMPLIFIER_ZERO: u16 = 32768;
+/// Voltage of one LSB of an auxiliary input in V.
+pub const AUX_LSB: f32 = 37.4e-6;
+/// Voltage of one LSB of the supply voltage sensor in V.
+pub const SUPPLY_LSB: f32 = 74.8e-6;
+/// LSBs per Kelvin of the difference of the two temperature sensor conversions.
+pub const TEMPERATURE_SCALE: f32 = 98.9;
+
+/// Input voltage of an amplifier result in V.
+/// `twos_comp` is the output format selected in register 4.
+pub fn amplifier_volts(sample: u16, twos_comp: bool) -> f32 {
+    let lsb = if twos_comp {
+        sample as i16 as i32
+    } else {
+        sample as i32 - AMPLIFIER_ZERO as i32
+    };
+    lsb as f32 * AMPLIFIER_LSB
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn amplifier_encodings_agree() {
+        for sample in [0u16, 1, 0x7fff, 0x8000, 0x8001, 0xffff] {
+            assert_eq!(
+                amplifier_volts(sample, false),
+                amplifier_volts(sample ^ 0x8000, true)
+            );
+        }
+        assert_eq!(amplifier_volts(AMPLIFIER_ZERO, false), 0.0);
+        assert_eq!(amplifier_volts(1000, true), 1000.0 * AMPLIFIER_LSB);
+    }
+}
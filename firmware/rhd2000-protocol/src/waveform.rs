@@ -0,0 +1,163 @@
+//! Synthetic signals for the simulator and for testing the data path of the firmware.
+//!
+//! Every value only depends on the channel and the sample number,
+//! so a receiver can check each sample exactly.
+//!
+//! A waveform is encoded in [`WAVEFORM_SIZE`] bytes: its kind followed by the parameters
+//! in little endian, padded with zeros. Kind 0 stands for the real samples of the chip.
+
+use core::f32::consts::PI;
+use libm::sinf;
+
+/// Size of an encoded [`Waveform`] in bytes.
+pub const WAVEFORM_SIZE: usize = 9;
+
+/// Synthetic signal on every channel. The values are offset binary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Waveform {
+    /// The same value on every channel.
+    Constant(u16),
+    /// Increases by one with every sample of a channel,
+    /// starting at 256 times the channel number.
+    Ramp,
+    /// Sine around mid scale with `amplitude` in LSB and a period of `period` samples.
+    /// The phase advances by 1/16 of a period from one channel to the next.
+    Sine {
+        /// Amplitude in LSB.
+        amplitude: f32,
+        /// Period in samples.
+        period: f32,
+    },
+    /// The sample number on every channel.
+    Counter,
+    /// Mid scale with a pulse of `amplitude` LSB every `period` samples,
+    /// starting with the first one.
+    Impulse {
+        /// Height of the pulses in LSB.
+        amplitude: u16,
+        /// Number of samples from one pulse to the next.
+        period: u32,
+    },
+}
+
+impl Waveform {
+    /// Value of sample `n` of `channel`.
+    pub fn sample(&self, channel: u8, n: u32) -> u16 {
+        match *self {
+            Self::Constant(v) => v,
+            Self::Ramp => ((channel as u32) << 8).wrapping_add(n) as u16,
+            Self::Sine { amplitude, period } => {
+                let phase = n as f32 / period + channel as f32 / 16.0;
+                (32768.0 + amplitude * sinf(2.0 * PI * phase)) as u16
+            }
+            Self::Counter => n as u16,
+            Self::Impulse { amplitude, period } => match n % period.max(1) {
+                0 => 32768u16.wrapping_add(amplitude),
+                _ => 32768,
+            },
+        }
+    }
+    /// Number of the kind of waveform in the encoding.
+    pub fn kind(&self) -> u8 {
+        match self {
+            Self::Constant(_) => 1,
+            Self::Ramp => 2,
+            Self::Sine { .. } => 3,
+            Self::Counter => 4,
+            Self::Impulse { .. } => 5,
+        }
+    }
+    /// Encode the kind and the parameters.
+    pub fn encode(&self) -> [u8; WAVEFORM_SIZE] {
+        let mut b = [0; WAVEFORM_SIZE];
+        b[0] = self.kind();
+        match *self {
+            Self::Constant(v) => b[1..3].copy_from_slice(&v.to_le_bytes()),
+            Self::Sine { amplitude, period } => {
+                b[1..5].copy_from_slice(&amplitude.to_le_bytes());
+                b[5..9].copy_from_slice(&period.to_le_bytes());
+            }
+            Self::Impulse { amplitude, period } => {
+                b[1..3].copy_from_slice(&amplitude.to_le_bytes());
+                b[3..7].copy_from_slice(&period.to_le_bytes());
+            }
+            Self::Ramp | Self::Counter => {}
+        }
+        b
+    }
+    /// Decode a waveform from [`Self::encode`].
+    /// Returns `None` for kind 0, unknown kinds and too short buffers.
+    pub fn decode(b: &[u8]) -> Option<Self> {
+        let b: &[u8; WAVEFORM_SIZE] = b.get(..WAVEFORM_SIZE)?.try_into().ok()?;
+        let u16_at = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+        Some(match b[0] {
+            1 => Self::Constant(u16_at(1)),
+            2 => Self::Ramp,
+            3 => Self::Sine {
+                amplitude: f32::from_bits(u32_at(1)),
+                period: f32::from_bits(u32_at(5)),
+            },
+            4 => Self::Counter,
+            5 => Self::Impulse {
+                amplitude: u16_at(1),
+                period: u32_at(3),
+            },
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_stays_in_range() {
+        let waveform = Waveform::Sine {
+            amplitude: 1000.0,
+            period: 20.0,
+        };
+        let samples: Vec<u16> = (0..20).map(|n| waveform.sample(0, n)).collect();
+        assert_eq!(samples[0], 32768);
+        assert!(samples.iter().all(|&v| v.abs_diff(32768) <= 1000));
+        assert!(samples[5].abs_diff(33768) <= 1);
+        assert_ne!(waveform.sample(1, 0), samples[0]);
+    }
+
+    #[test]
+    fn impulse_train() {
+        let waveform = Waveform::Impulse {
+            amplitude: 5000,
+            period: 4,
+        };
+        let samples: Vec<u16> = (0..9).map(|n| waveform.sample(3, n)).collect();
+        assert_eq!(samples.iter().filter(|&&v| v == 37768).count(), 3);
+        assert_eq!(samples[4], 37768);
+        assert_eq!(samples[5], 32768);
+        assert_eq!(Waveform::Counter.sample(9, 70000), 70000u32 as u16);
+    }
+
+    #[test]
+    fn encoding_round_trip() {
+        let waveforms = [
+            Waveform::Constant(1234),
+            Waveform::Ramp,
+            Waveform::Sine {
+                amplitude: 500.0,
+                period: 37.5,
+            },
+            Waveform::Counter,
+            Waveform::Impulse {
+                amplitude: 300,
+                period: 100_000,
+            },
+        ];
+        for waveform in waveforms {
+            assert_eq!(Waveform::decode(&waveform.encode()), Some(waveform));
+        }
+        assert_eq!(Waveform::decode(&[0; WAVEFORM_SIZE]), None);
+        assert_eq!(Waveform::decode(&[2]), None);
+    }
+}
@@ -11,6 +11,7 @@ const app = Vue.createApp({
       start: 0,
       transferred: 0,
       plots: [],
+      scale: null,
       running: false,
       recording: [],
       recordingSize: 0
@@ -39,6 +40,13 @@ const app = Vue.createApp({
         }
       }
     },
+    sampleValue(raw, signed) {
+      if (signed && raw >= 32768) raw -= 65536
+      // Microvolts once the configuration is known, full scale otherwise
+      return this.scale
+        ? (raw - this.scale.offset) * this.scale.lsb * 1e6
+        : (signed ? raw : raw - 32768) / 32768
+    },
     clearPlots(count) {
       let plots = []
       for (let i = 0; i < count; ++i) {
@@ -62,11 +70,32 @@ const app = Vue.createApp({
           if (d.status === 'ok') {
             this.transferred += d.data.byteLength
             this.recordPacket(d.data)
-            if (d.data.byteLength > 2) {
-              let channels = d.data.getUint8(1)
+            // Packet header version 1, see doc/DataFormat.md
+            let valid = d.data.byteLength >= 28 &&
+              d.data.getUint16(0, true) === 0x4942 && d.data.getUint8(2) === 1
+            let type = valid ? d.data.getUint8(3) : -1
+            if (type === 3 && d.data.byteLength >= 60) {
+              this.scale = {
+                offset: d.data.getInt32(52, true),
+                lsb: d.data.getFloat32(56, true)
+              }
+            }
+            // Compressed samples (encoding 1) are not shown
+            let encoding = valid ? d.data.getUint8(27) : 0
+            if (type === 0 && (encoding & 15) === 0 && d.data.byteLength > 44) {
+              let channels = d.data.getUint8(24)
+              let signed = d.data.getUint8(25) === 1
+              // Packed with the bit depth of the header, 16 bit words at full depth
+              let bits = 16 - (encoding >> 4)
               let frame = []
-              for (let i = 0; i < Math.floor((d.data.byteLength - 2) / 2); ++i) {
-                let v = (d.data.getUint16(2 * i + 2, true) - 32768) / 32768
+              for (let i = 0; i < Math.floor((d.data.byteLength - 44) * 8 / bits); ++i) {
+                let bit = 44 * 8 + i * bits
+                let word = 0
+                for (let j = 0; j < 3 && (bit >> 3) + j < d.data.byteLength; ++j) {
+                  word |= d.data.getUint8((bit >> 3) + j) << (8 * j)
+                }
+                let raw = ((word >> (bit & 7)) & ((1 << bits) - 1)) << (16 - bits)
+                let v = this.sampleValue(raw, signed)
                 if (frame.length < channels) {
                   frame.push([v, v])
                 } else {
@@ -77,6 +106,23 @@ const app = Vue.createApp({
               }
               this.liveViewFrame(frame)
             }
+            // Envelope points, a pair of 16 bit min and max per channel
+            if (type === 6 && d.data.byteLength > 44) {
+              let channels = d.data.getUint8(24)
+              let signed = d.data.getUint8(25) === 1
+              let points = Math.floor((d.data.byteLength - 44) / (4 * channels))
+              for (let p = 0; p < points; ++p) {
+                let frame = []
+                for (let c = 0; c < channels; ++c) {
+                  let offset = 44 + 4 * (p * channels + c)
+                  frame.push([
+                    this.sampleValue(d.data.getUint16(offset, true), signed),
+                    this.sampleValue(d.data.getUint16(offset + 2, true), signed)
+                  ])
+                }
+                this.liveViewFrame(frame)
+              }
+            }
           }
         }
       } catch (e) {